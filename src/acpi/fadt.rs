@@ -0,0 +1,80 @@
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u32, read_u64, GenericAddress};
+
+/// `RESET_REG_SUP`: the reset register is supported
+const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+/// `8042` in the IA-PC boot architecture flags: the system has a PS/2 keyboard controller
+const HAS_8042: u16 = 1 << 1;
+/// The length up to the PM timer port, later fields are treated as absent if the table is shorter
+const MINIMUM_LENGTH: usize = 80;
+
+/// The fixed ACPI description table, which describes the power management hardware
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    /// The physical address of the differentiated system description table
+    pub dsdt: PhysAddr,
+    /// The ISA interrupt of the system control interrupt
+    pub sci_interrupt: u16,
+    /// The I/O port used to enable ACPI mode, or 0 if the system is always in ACPI mode
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    /// The I/O ports of the PM1a and PM1b control registers, PM1b is 0 if it doesn't exist
+    pub pm1a_control: u32,
+    pub pm1b_control: u32,
+    /// The I/O port of the ACPI power management timer, or 0 if it doesn't exist
+    pub pm_timer: u32,
+    /// The CMOS RTC index of the century, or 0 if it isn't supported
+    pub century: u8,
+    /// Whether the system has a PS/2 keyboard controller
+    pub has_8042: bool,
+    /// The register that resets the system when `reset_value` is written to it
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Returns `None` if the table is too short
+    pub(super) fn parse(table: &[u8]) -> Option<Fadt> {
+        if table.len() < MINIMUM_LENGTH {
+            return None;
+        }
+
+        let mut dsdt = u64::from(read_u32(table, 40));
+        // The 64-bit DSDT address of ACPI 2.0 takes precedence
+        if table.len() >= 148 && read_u64(table, 140) != 0 {
+            dsdt = read_u64(table, 140);
+        }
+
+        let flags = if table.len() >= 116 {
+            read_u32(table, 112)
+        } else {
+            0
+        };
+        let reset_register = if flags & RESET_REGISTER_SUPPORTED != 0 && table.len() >= 129 {
+            Some(GenericAddress::parse(&table[116..128]))
+        } else {
+            None
+        };
+
+        Some(Fadt {
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: read_u16(table, 46),
+            smi_command: read_u32(table, 48),
+            acpi_enable: table[52],
+            pm1a_control: read_u32(table, 64),
+            pm1b_control: read_u32(table, 68),
+            pm_timer: read_u32(table, 76),
+            century: table.get(108).cloned().unwrap_or(0),
+            // The boot architecture flags don't exist in ACPI 1.0, where the 8042 is assumed
+            has_8042: table[8] < 2 || table.len() < 111 || read_u16(table, 109) & HAS_8042 != 0,
+            reset_register,
+            reset_value: if reset_register.is_some() {
+                table[128]
+            } else {
+                0
+            },
+        })
+    }
+}
@@ -0,0 +1,37 @@
+use super::{read_u16, read_u32, GenericAddress};
+
+/// The length of the table, which ends with the page protection byte
+const LENGTH: usize = 56;
+
+/// The HPET description table, which describes a high precision event timer block
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Hpet {
+    /// The address of the timer registers, in system memory
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// The amount of comparators of the timer block
+    pub comparators: u8,
+    /// Whether the main counter is 64 bits wide
+    pub has_64bit_counter: bool,
+    /// The minimum tick in periodic mode without losing interrupts
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// Returns `None` if the table is too short
+    pub(super) fn parse(table: &[u8]) -> Option<Hpet> {
+        if table.len() < LENGTH {
+            return None;
+        }
+        let block_id = read_u32(table, 36);
+
+        Some(Hpet {
+            base_address: GenericAddress::parse(&table[40..52]),
+            hpet_number: table[52],
+            comparators: ((block_id >> 8) & 0x1f) as u8 + 1,
+            has_64bit_counter: block_id & (1 << 13) != 0,
+            minimum_tick: read_u16(table, 53),
+        })
+    }
+}
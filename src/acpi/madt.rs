@@ -0,0 +1,129 @@
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u32, read_u64, HEADER_SIZE};
+
+/// The multiple APIC description table, which lists the interrupt controllers
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct Madt {
+    /// The physical address of the local APIC registers
+    pub local_apic_address: PhysAddr,
+    /// Whether the system also has the legacy 8259 PICs
+    pub has_legacy_pics: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+/// A processor and its local APIC
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the processor can be used
+    pub enabled: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    /// The physical address of the I/O APIC registers
+    pub address: PhysAddr,
+    /// The first global system interrupt handled by the I/O APIC
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt that isn't identity mapped to a global system interrupt
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+impl Madt {
+    /// Returns `None` if the table is too short, entries that are too short are skipped
+    pub(super) fn parse(table: &[u8]) -> Option<Madt> {
+        if table.len() < HEADER_SIZE + 8 {
+            return None;
+        }
+
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(read_u32(table, HEADER_SIZE))),
+            has_legacy_pics: read_u32(table, HEADER_SIZE + 4) & 1 != 0,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        let mut offset = HEADER_SIZE + 8;
+        while offset + 2 <= table.len() {
+            let entry_type = table[offset];
+            let length = table[offset + 1] as usize;
+            if length < 2 || offset + length > table.len() {
+                break;
+            }
+            let entry = &table[offset..offset + length];
+
+            match entry_type {
+                0 if length >= 8 => madt.local_apics.push(LocalApic {
+                    processor_id: entry[2],
+                    apic_id: entry[3],
+                    enabled: read_u32(entry, 4) & 1 != 0,
+                }),
+                1 if length >= 12 => madt.io_apics.push(IoApic {
+                    id: entry[2],
+                    address: PhysAddr::new(u64::from(read_u32(entry, 4))),
+                    gsi_base: read_u32(entry, 8),
+                }),
+                2 if length >= 10 => {
+                    let flags = read_u16(entry, 8);
+                    madt.overrides.push(InterruptSourceOverride {
+                        isa_irq: entry[3],
+                        gsi: read_u32(entry, 4),
+                        // ISA interrupts are active high and edge triggered by default
+                        polarity: match flags & 0b11 {
+                            0b11 => Polarity::ActiveLow,
+                            _ => Polarity::ActiveHigh,
+                        },
+                        trigger_mode: match (flags >> 2) & 0b11 {
+                            0b11 => TriggerMode::Level,
+                            _ => TriggerMode::Edge,
+                        },
+                    });
+                }
+                // Local APIC address override
+                5 if length >= 12 => madt.local_apic_address = PhysAddr::new(read_u64(entry, 4)),
+                _ => {}
+            }
+
+            offset += length;
+        }
+
+        Some(madt)
+    }
+
+    /// Get the global system interrupt, polarity and trigger mode of an ISA interrupt
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        match self.overrides.iter().find(|entry| entry.isa_irq == irq) {
+            Some(entry) => (entry.gsi, entry.polarity, entry.trigger_mode),
+            None => (u32::from(irq), Polarity::ActiveHigh, TriggerMode::Edge),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+mod fadt;
+mod hpet;
+mod madt;
+mod power;
+mod rsdp;
+
+use core::{convert::TryInto, slice, str};
+
+use alloc::vec::Vec;
+use multiboot2::BootInformation;
+use spin::Once;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+
+use crate::memory::MemoryController;
+
+pub use self::fadt::Fadt;
+pub use self::hpet::Hpet;
+pub use self::madt::{Madt, Polarity, TriggerMode};
+pub use self::power::{reboot, shutdown};
+
+use self::rsdp::RootTable;
+
+/// The size of the header shared by all system description tables
+const HEADER_SIZE: usize = 36;
+
+static MADT: Once<Madt> = Once::new();
+static FADT: Once<Fadt> = Once::new();
+static HPET: Once<Hpet> = Once::new();
+
+/// The address of a register in a generic address structure
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenericAddress {
+    Memory(PhysAddr),
+    Io(u16),
+    /// An address space that isn't supported, with its ID
+    Unsupported(u8),
+}
+
+impl GenericAddress {
+    fn parse(bytes: &[u8]) -> GenericAddress {
+        let address = read_u64(bytes, 4);
+        match bytes[0] {
+            0 => GenericAddress::Memory(PhysAddr::new(address)),
+            1 => GenericAddress::Io(address as u16),
+            id => GenericAddress::Unsupported(id),
+        }
+    }
+}
+
+/// Find and parse the ACPI tables
+pub fn init(boot_info: &BootInformation, memory_controller: &mut MemoryController) {
+    let root_table = match rsdp::find(boot_info, memory_controller) {
+        Some(root_table) => root_table,
+        None => {
+            println!("ACPI: no RSDP found");
+            return;
+        }
+    };
+
+    let (root_address, entry_size) = match root_table {
+        RootTable::Rsdt(address) => (address, 4),
+        RootTable::Xsdt(address) => (address, 8),
+    };
+    let table_addresses = match read_table(memory_controller, root_address, |root| {
+        root[HEADER_SIZE..]
+            .chunks_exact(entry_size)
+            .map(|entry| match entry_size {
+                4 => u64::from(read_u32(entry, 0)),
+                _ => read_u64(entry, 0),
+            })
+            .map(PhysAddr::new)
+            .collect::<Vec<_>>()
+    }) {
+        Some(table_addresses) => table_addresses,
+        None => return,
+    };
+
+    print!("ACPI tables:");
+    for address in table_addresses {
+        let signature = match read_table_header(memory_controller, address) {
+            Some(signature) => signature,
+            None => continue,
+        };
+        print!(" {}", str::from_utf8(&signature).unwrap_or("????"));
+
+        match &signature {
+            b"APIC" => {
+                if let Some(Some(madt)) = read_table(memory_controller, address, Madt::parse) {
+                    MADT.call_once(|| madt);
+                }
+            }
+            b"FACP" => {
+                if let Some(Some(fadt)) = read_table(memory_controller, address, Fadt::parse) {
+                    FADT.call_once(|| fadt);
+                }
+            }
+            b"HPET" => {
+                if let Some(Some(hpet)) = read_table(memory_controller, address, Hpet::parse) {
+                    HPET.call_once(|| hpet);
+                }
+            }
+            _ => {}
+        }
+    }
+    println!();
+
+    if let Some(fadt) = FADT.get() {
+        power::init(fadt, memory_controller);
+    }
+}
+
+/// The parsed MADT, if the ACPI tables were found
+pub fn madt() -> Option<&'static Madt> {
+    MADT.get()
+}
+
+/// The parsed FADT, if the ACPI tables were found
+pub fn fadt() -> Option<&'static Fadt> {
+    FADT.get()
+}
+
+/// The parsed HPET table, if the system has a HPET
+#[allow(dead_code)]
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+/// Get the signature of the table at `address`, without mapping the whole table
+fn read_table_header(
+    memory_controller: &mut MemoryController,
+    address: PhysAddr,
+) -> Option<[u8; 4]> {
+    read_physical(memory_controller, address, HEADER_SIZE, |header| {
+        header[0..4].try_into().unwrap()
+    })
+}
+
+/// Map the table at `address` while `f` reads it
+///
+/// Returns `None` if the table couldn't be mapped or its checksum is invalid
+fn read_table<T, F: FnOnce(&[u8]) -> T>(
+    memory_controller: &mut MemoryController,
+    address: PhysAddr,
+    f: F,
+) -> Option<T> {
+    let length = read_physical(memory_controller, address, HEADER_SIZE, |header| {
+        read_u32(header, 4) as usize
+    })?;
+    if length < HEADER_SIZE {
+        return None;
+    }
+
+    read_physical(memory_controller, address, length, |table| {
+        if checksum_is_valid(table) {
+            Some(f(table))
+        } else {
+            println!(
+                "ACPI: invalid checksum of the {} table",
+                str::from_utf8(&table[0..4]).unwrap_or("????")
+            );
+            None
+        }
+    })?
+}
+
+/// Map `length` bytes of physical memory while `f` reads them
+fn read_physical<T, F: FnOnce(&[u8]) -> T>(
+    memory_controller: &mut MemoryController,
+    address: PhysAddr,
+    length: usize,
+    f: F,
+) -> Option<T> {
+    let start = memory_controller.map_physical_region(
+        "ACPI table",
+        address,
+        length as u64,
+        PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+    )?;
+
+    let result = f(unsafe { slice::from_raw_parts(start.as_ptr(), length) });
+    unsafe { memory_controller.unmap_physical_region(start) };
+
+    Some(result)
+}
+
+/// Whether the bytes of a table sum up to 0
+fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
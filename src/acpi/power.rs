@@ -0,0 +1,186 @@
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::{hlt, interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+use crate::memory::{CacheType, IoMemory, MemoryController};
+
+use super::{read_table, read_u16, Fadt, GenericAddress, HEADER_SIZE};
+
+/// `SCI_EN` in the PM1 control register: the system is in ACPI mode
+const SCI_ENABLE: u16 = 1 << 0;
+/// `SLP_TYPx` and `SLP_EN` in the PM1 control registers
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+/// How often the PM1a control register is polled while waiting for ACPI mode
+const ACPI_ENABLE_ATTEMPTS: usize = 1_000_000;
+
+/// The status and command port of the PS/2 keyboard controller
+const KEYBOARD_CONTROLLER_PORT: u16 = 0x64;
+/// The input buffer of the keyboard controller is full and can't take a command
+const KEYBOARD_INPUT_FULL: u8 = 1 << 1;
+/// How often the keyboard controller is polled until it accepts a command
+const KEYBOARD_ATTEMPTS: usize = 1_000_000;
+/// The keyboard controller command pulsing the reset line of the CPU
+const KEYBOARD_RESET: u8 = 0xfe;
+
+// AML opcodes used in the `\_S5` object
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_CHAR: u8 = b'\\';
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+
+/// The `SLP_TYPa` and `SLP_TYPb` values of the soft off sleeping state
+static S5: Once<(u16, u16)> = Once::new();
+/// The reset register, if it's memory mapped
+static RESET_REGISTER: Once<Mutex<IoMemory>> = Once::new();
+
+/// Find the sleep types of the soft off state and map the reset register
+pub(super) fn init(fadt: &Fadt, memory_controller: &mut MemoryController) {
+    match read_table(memory_controller, fadt.dsdt, find_s5) {
+        Some(Some(sleep_types)) => {
+            S5.call_once(|| sleep_types);
+        }
+        _ => println!("ACPI: no \\_S5 object found, shutdown isn't supported"),
+    }
+
+    if let Some(GenericAddress::Memory(address)) = fadt.reset_register {
+        if let Some(register) =
+            memory_controller.ioremap("ACPI reset register", address, 1, CacheType::Uncached)
+        {
+            RESET_REGISTER.call_once(|| Mutex::new(register));
+        }
+    }
+}
+
+/// Find the `\_S5` package in the AML of the DSDT, and read its first two elements
+///
+/// The package is `NameOp "_S5_" PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb ...`
+fn find_s5(dsdt: &[u8]) -> Option<(u16, u16)> {
+    let aml = &dsdt[HEADER_SIZE..];
+    let position = aml.windows(4).position(|name| name == b"_S5_")?;
+
+    // The name must be defined by a `NameOp`, optionally with a root prefix
+    let name_op = match position {
+        0 => return None,
+        1 => aml[0],
+        _ if aml[position - 1] == AML_ROOT_CHAR => aml[position - 2],
+        _ => aml[position - 1],
+    };
+    if name_op != AML_NAME_OP {
+        return None;
+    }
+
+    let mut package = aml.get(position + 4..)?;
+    if *package.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+    // The two high bits of the first byte of `PkgLength` are the amount of bytes that follow it
+    let length_bytes = (*package.get(1)? >> 6) as usize + 1;
+    // Skip the opcode, `PkgLength` and `NumElements`
+    package = package.get(1 + length_bytes + 1..)?;
+
+    let (sleep_type_a, package) = read_integer(package)?;
+    let (sleep_type_b, _) = read_integer(package)?;
+
+    Some((sleep_type_a, sleep_type_b))
+}
+
+/// Read an AML integer constant, and return it with the remaining bytes
+fn read_integer(aml: &[u8]) -> Option<(u16, &[u8])> {
+    match *aml.first()? {
+        AML_ZERO_OP => Some((0, &aml[1..])),
+        AML_ONE_OP => Some((1, &aml[1..])),
+        AML_BYTE_PREFIX => Some((u16::from(*aml.get(1)?), aml.get(2..)?)),
+        AML_WORD_PREFIX => Some((read_u16(aml.get(1..3)?, 0), aml.get(3..)?)),
+        _ => None,
+    }
+}
+
+/// Turn the system off by entering the ACPI soft off state
+///
+/// Halts forever if ACPI shutdown isn't supported or doesn't work
+pub fn shutdown() -> ! {
+    if let (Some(fadt), Some(&(sleep_type_a, sleep_type_b))) = (super::fadt(), S5.get()) {
+        interrupts::disable();
+
+        unsafe {
+            let mut pm1a_control = Port::<u16>::new(fadt.pm1a_control as u16);
+
+            // The firmware handles power management until ACPI mode is enabled
+            if fadt.smi_command != 0 && pm1a_control.read() & SCI_ENABLE == 0 {
+                Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable);
+                for _ in 0..ACPI_ENABLE_ATTEMPTS {
+                    if pm1a_control.read() & SCI_ENABLE != 0 {
+                        break;
+                    }
+                }
+            }
+
+            pm1a_control.write((sleep_type_a << SLEEP_TYPE_SHIFT) | SLEEP_ENABLE);
+            if fadt.pm1b_control != 0 {
+                Port::<u16>::new(fadt.pm1b_control as u16)
+                    .write((sleep_type_b << SLEEP_TYPE_SHIFT) | SLEEP_ENABLE);
+            }
+        }
+    }
+
+    println!("Shutdown failed, it is now safe to turn off the computer");
+    loop {
+        interrupts::disable();
+        hlt();
+    }
+}
+
+/// Restart the system
+///
+/// Tries the ACPI reset register, then the keyboard controller, and finally causes a triple fault
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Some(fadt) = super::fadt() {
+        match fadt.reset_register {
+            Some(GenericAddress::Io(port)) => unsafe {
+                Port::<u8>::new(port).write(fadt.reset_value)
+            },
+            Some(GenericAddress::Memory(_)) => {
+                if let Some(register) = RESET_REGISTER.get() {
+                    register.lock().write(0, fadt.reset_value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if super::fadt().map_or(true, |fadt| fadt.has_8042) {
+        let mut port = Port::<u8>::new(KEYBOARD_CONTROLLER_PORT);
+        unsafe {
+            for _ in 0..KEYBOARD_ATTEMPTS {
+                if port.read() & KEYBOARD_INPUT_FULL == 0 {
+                    port.write(KEYBOARD_RESET);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Any interrupt with an empty IDT causes a triple fault, which resets the CPU
+    let empty_idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty_idt);
+        interrupts::int3();
+    }
+
+    loop {
+        hlt();
+    }
+}
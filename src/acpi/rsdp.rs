@@ -0,0 +1,92 @@
+use multiboot2::BootInformation;
+use x86_64::PhysAddr;
+
+use crate::memory::MemoryController;
+
+use super::{checksum_is_valid, read_physical, read_u16, read_u32, read_u64};
+
+/// The physical address of the segment of the extended BIOS data area, in the BIOS data area
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+/// The part of the extended BIOS data area that may contain the RSDP
+const EBDA_SEARCH_LENGTH: usize = 1024;
+/// The BIOS read-only memory, which may contain the RSDP
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_LENGTH: usize = 0x20000;
+
+/// The size of the RSDP of ACPI 1.0, and of later versions
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+/// The table listing all other system description tables
+#[derive(Debug, Clone, Copy)]
+pub enum RootTable {
+    /// The root system description table, with 32-bit table addresses
+    Rsdt(PhysAddr),
+    /// The extended system description table, with 64-bit table addresses
+    Xsdt(PhysAddr),
+}
+
+/// Find the root table through the RSDP from the boot loader, or by searching the BIOS areas
+pub fn find(
+    boot_info: &BootInformation,
+    memory_controller: &mut MemoryController,
+) -> Option<RootTable> {
+    if let Some(tag) = boot_info.rsdp_v2_tag() {
+        if tag.checksum_is_valid() && tag.xsdt_address() != 0 {
+            return Some(RootTable::Xsdt(PhysAddr::new(tag.xsdt_address() as u64)));
+        }
+    }
+    if let Some(tag) = boot_info.rsdp_v1_tag() {
+        if tag.checksum_is_valid() {
+            return Some(RootTable::Rsdt(PhysAddr::new(tag.rsdt_address() as u64)));
+        }
+    }
+
+    let ebda_segment = read_physical(
+        memory_controller,
+        PhysAddr::new(EBDA_SEGMENT_POINTER),
+        2,
+        |bytes| read_u16(bytes, 0),
+    )?;
+    let ebda = PhysAddr::new(u64::from(ebda_segment) << 4);
+
+    let in_ebda = if ebda_segment != 0 {
+        search(memory_controller, ebda, EBDA_SEARCH_LENGTH)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| {
+        search(
+            memory_controller,
+            PhysAddr::new(BIOS_AREA_START),
+            BIOS_AREA_LENGTH,
+        )
+    })
+}
+
+/// Search an area for the RSDP, which is aligned to 16 bytes
+fn search(
+    memory_controller: &mut MemoryController,
+    start: PhysAddr,
+    length: usize,
+) -> Option<RootTable> {
+    read_physical(memory_controller, start, length, |area| {
+        (0..area.len() - RSDP_V1_SIZE)
+            .step_by(16)
+            .map(|offset| &area[offset..])
+            .filter(|rsdp| &rsdp[0..8] == b"RSD PTR " && checksum_is_valid(&rsdp[..RSDP_V1_SIZE]))
+            .map(|rsdp| {
+                let revision = rsdp[15];
+                if revision >= 2
+                    && rsdp.len() >= RSDP_V2_SIZE
+                    && checksum_is_valid(&rsdp[..RSDP_V2_SIZE])
+                    && read_u64(rsdp, 24) != 0
+                {
+                    RootTable::Xsdt(PhysAddr::new(read_u64(rsdp, 24)))
+                } else {
+                    RootTable::Rsdt(PhysAddr::new(u64::from(read_u32(rsdp, 16))))
+                }
+            })
+            .next()
+    })?
+}
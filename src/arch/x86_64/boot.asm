@@ -1,10 +1,15 @@
 global start
 extern long_mode_start
 
-section .text
+; The virtual address the kernel is linked at, see `linker.ld`
+KERNEL_OFFSET equ 0xffffffff80000000
+
+; This code runs before the higher half is mapped, so it's linked at its physical address, and must
+; access higher half symbols through their physical address
+section .boot.text
 bits 32
 start:
-  mov esp, stack_top
+  mov esp, stack_top - KERNEL_OFFSET
   mov edi, ebx
 
   call check_multiboot
@@ -91,20 +96,23 @@ check_long_mode:
   jmp error
 
 set_up_page_tables:
-  ; Map last P4 entry to itself
-  mov eax, p4_table
+  ; Map the second to last P4 entry to itself, the last one is used by the kernel
+  mov eax, p4_table - KERNEL_OFFSET
   or eax, 0b11 ; present + writable
-  mov [p4_table + 511 * 8], eax
+  mov [p4_table - KERNEL_OFFSET + 510 * 8], eax
 
-  ; Map first P4 entry to P3 table
-  mov eax, p3_table
+  ; Map the first and last P4 entries to the P3 table
+  mov eax, p3_table - KERNEL_OFFSET
   or eax, 0b11 ; present + writable
-  mov [p4_table], eax
+  mov [p4_table - KERNEL_OFFSET], eax
+  mov [p4_table - KERNEL_OFFSET + 511 * 8], eax
 
-  ; Map first P3 entry to P2 table
-  mov eax, p2_table
+  ; Map the first and second to last P3 entries to the P2 table, so the first GiB is mapped both at
+  ; its physical address (for the boot code) and at `KERNEL_OFFSET` (for the rest of the kernel)
+  mov eax, p2_table - KERNEL_OFFSET
   or eax, 0b11 ; present + writable
-  mov [p3_table], eax
+  mov [p3_table - KERNEL_OFFSET], eax
+  mov [p3_table - KERNEL_OFFSET + 510 * 8], eax
 
   ; Map each P2 entry to a huge 2MiB page
   mov ecx, 0         ; counter variable
@@ -113,7 +121,7 @@ set_up_page_tables:
   mov eax, 0x200000  ; 2MiB
   mul ecx            ; start address of ecx-th page
   or eax, 0b10000011 ; present + writable + huge
-  mov [p2_table + ecx * 8], eax ; map ecx-th entry
+  mov [p2_table - KERNEL_OFFSET + ecx * 8], eax ; map ecx-th entry
 
   inc ecx            ; increase counter
   cmp ecx, 512       ; if counter == 512, the whole P2 table is mapped
@@ -123,7 +131,7 @@ set_up_page_tables:
 
 enable_paging:
   ; Load P4 to cr3 register (cpu uses this to access the P4 table)
-  mov eax, p4_table
+  mov eax, p4_table - KERNEL_OFFSET
   mov cr3, eax
 
   ; Enable PAE-flag in cr4 (Physical Address Extension)
@@ -144,7 +152,7 @@ enable_paging:
 
   ret
 
-section .rodata
+section .boot.rodata
 gdt64:
   dq 0 ; zero entry
 .code: equ $ - gdt64
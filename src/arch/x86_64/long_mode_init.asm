@@ -1,6 +1,8 @@
 global long_mode_start
 
-section .text
+KERNEL_OFFSET equ 0xffffffff80000000
+
+section .boot.text
 bits 64
 long_mode_start:
   ; Load 0 into all data segment registers
@@ -11,6 +13,22 @@ long_mode_start:
   mov fs, ax
   mov gs, ax
 
+  ; Jump to the higher half
+  mov rax, higher_half_start
+  jmp rax
+
+section .text
+bits 64
+higher_half_start:
+  ; Move the stack pointer to the higher half
+  mov rax, KERNEL_OFFSET
+  add rsp, rax
+
+  ; Pass the multiboot information through its higher half address. The upper half of `rdi` is
+  ; undefined after switching to long mode, so clear it first
+  mov edi, edi
+  add rdi, rax
+
   ; Call the Rust main function
   extern rust_main
   call rust_main
@@ -1,134 +0,0 @@
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, layouts, ScancodeSet1};
-use pic8259::ChainedPics;
-use spin::{Lazy, Mutex, Once};
-use x86_64::{
-    instructions::{hlt, port::Port, tables::load_tss},
-    registers::segmentation::{CS, Segment},
-    structures::{
-        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
-        idt::{InterruptDescriptorTable, InterruptStackFrame},
-        tss::TaskStateSegment,
-    },
-};
-
-use crate::memory::MemoryController;
-
-const PIC_1_OFFSET: u8 = 32;
-const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-
-static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
-
-static KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(Keyboard::new(
-    ScancodeSet1::new(),
-    layouts::Us104Key,
-    HandleControl::Ignore,
-));
-
-const DOUBLE_FAULT_IST_INDEX: usize = 0;
-
-static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
-    let mut idt = InterruptDescriptorTable::new();
-    idt.breakpoint.set_handler_fn(breakpoint_handler);
-    unsafe {
-        idt.double_fault
-            .set_handler_fn(double_fault_handler)
-            .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16)
-    };
-    idt[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
-    idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
-
-    idt
-});
-
-static TSS: Once<TaskStateSegment> = Once::new();
-static GDT: Once<GlobalDescriptorTable> = Once::new();
-
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
-pub fn init(memory_controller: &mut MemoryController) {
-    let double_fault_stack = memory_controller
-        .allocate_stack(2)
-        .expect("Failed to allocate double fault stack");
-
-    let tss = TSS.call_once(|| {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = double_fault_stack.top();
-        tss
-    });
-
-    let mut code_selector = SegmentSelector(0);
-    let mut tss_selector = SegmentSelector(0);
-    let gdt = GDT.call_once(|| {
-        let mut gdt = GlobalDescriptorTable::new();
-        code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
-        gdt
-    });
-    gdt.load();
-
-    unsafe {
-        // Reload code segment register
-        CS::set_reg(code_selector);
-        // Load TSS
-        load_tss(tss_selector);
-    }
-
-    IDT.load();
-
-    unsafe {
-        PICS.lock().initialize();
-    }
-
-
-    x86_64::instructions::interrupts::enable();
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("Exception: Breakpoint");
-    println!("  Stack frame: {:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    println!("Exception: Double fault");
-    println!("  Error code: {}", error_code);
-    println!("  Stack frame: {:#?}", stack_frame);
-
-    loop {
-        hlt();
-    }
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
-    }
-}
-
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-
-    let mut keyboard = KEYBOARD.lock();
-
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            if let DecodedKey::Unicode(character) = key {
-                print!("{}", character);
-            }
-        }
-    }
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
-    }
-}
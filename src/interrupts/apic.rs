@@ -0,0 +1,261 @@
+use core::arch::x86_64::__cpuid;
+
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+use crate::acpi::{self, Polarity, TriggerMode};
+use crate::memory::{CacheType, IoMemory, MemoryController};
+
+/// The MSR containing the physical address of the local APIC and its enable bit
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TASK_PRIORITY: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SPURIOUS_VECTOR: u64 = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+// Local APIC timer modes, in bits 17 and 18 of the timer register
+const TIMER_ONE_SHOT: u32 = 0b00 << 17;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+/// The divide configuration dividing the timer clock by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// The MSR holding the TSC value at which the timer fires in TSC-deadline mode
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+// I/O APIC registers, which are accessed through a register select and a data window
+const IOAPIC_REGISTER_SELECT: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+// Redirection entry bits
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The vector of spurious local APIC interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The local APIC registers, which are accessed without a lock so interrupt handlers can always
+/// signal the end of an interrupt
+static LOCAL_APIC: Once<IoMemory> = Once::new();
+static IO_APICS: Once<Mutex<Vec<IoApic>>> = Once::new();
+
+struct IoApic {
+    registers: IoMemory,
+    gsi_base: u32,
+    /// The amount of redirection entries
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        self.registers.write(IOAPIC_REGISTER_SELECT, register);
+        self.registers.read(IOAPIC_WINDOW)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.registers.write(IOAPIC_REGISTER_SELECT, register);
+        self.registers.write(IOAPIC_WINDOW, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn set_redirection(&mut self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // Mask the entry while it is changed
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// How the local APIC timer decides when to interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Once, when a count set by `start_timer` reaches 0
+    OneShot,
+    /// Once, when the TSC reaches a value set by `set_tsc_deadline`
+    TscDeadline,
+}
+
+/// Whether the CPU has a local APIC
+#[allow(unused_unsafe)]
+pub fn is_supported() -> bool {
+    // CPUID.01H:EDX.APIC[bit 9]
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// Whether interrupts are delivered through the APIC instead of the legacy PICs
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.get().is_some()
+}
+
+/// Enable the local APIC and the I/O APICs listed in the MADT, and mask all their interrupts
+///
+/// Returns whether the APIC can be used, the legacy PICs must be used otherwise
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    let madt = match acpi::madt() {
+        Some(madt) if is_supported() && !madt.io_apics.is_empty() => madt,
+        _ => return false,
+    };
+
+    let mut base = Msr::new(IA32_APIC_BASE);
+    let base_value = unsafe { base.read() };
+    unsafe { base.write(base_value | APIC_BASE_ENABLE) };
+
+    let local_apic = match memory_controller.ioremap(
+        "local APIC",
+        PhysAddr::new(base_value & 0x000f_ffff_ffff_f000),
+        0x400,
+        CacheType::Uncached,
+    ) {
+        Some(local_apic) => local_apic,
+        None => return false,
+    };
+    // Accept all interrupts and software enable the APIC
+    local_apic.write(LAPIC_TASK_PRIORITY, 0u32);
+    local_apic.write(
+        LAPIC_SPURIOUS_VECTOR,
+        LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+
+    let mut io_apics = Vec::new();
+    for entry in madt.io_apics.iter() {
+        let registers = memory_controller
+            .ioremap("I/O APIC", entry.address, 0x20, CacheType::Uncached)
+            .expect("Failed to map I/O APIC");
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base: entry.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+
+    IO_APICS.call_once(|| Mutex::new(io_apics));
+    LOCAL_APIC.call_once(|| local_apic);
+
+    true
+}
+
+/// Deliver an ISA interrupt to `vector` on this CPU, applying the MADT interrupt source overrides
+pub fn route_isa_irq(irq: u8, vector: u8) {
+    let (gsi, polarity, trigger_mode) = acpi::madt().expect("APIC used without MADT").isa_irq(irq);
+
+    if !route_gsi(gsi, vector, polarity, trigger_mode) {
+        println!("No I/O APIC handles GSI {} (IRQ {})", gsi, irq);
+    }
+}
+
+/// Stop delivering an ISA interrupt
+pub fn mask_isa_irq(irq: u8) {
+    let (gsi, _, _) = acpi::madt().expect("APIC used without MADT").isa_irq(irq);
+
+    let mut io_apics = IO_APICS.get().expect("APIC not initialized").lock();
+    if let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+    }
+}
+
+/// Deliver a global system interrupt to `vector` on this CPU
+///
+/// Returns `false` if no I/O APIC handles the interrupt
+pub fn route_gsi(gsi: u32, vector: u8, polarity: Polarity, trigger_mode: TriggerMode) -> bool {
+    let apic_id = local_apic().read::<u32>(LAPIC_ID) >> 24;
+
+    // Fixed delivery to the physical APIC ID in the high bits
+    let mut entry = u64::from(vector) | (u64::from(apic_id) << 56);
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger_mode == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+
+    let mut io_apics = IO_APICS.get().expect("APIC not initialized").lock();
+    match io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        Some(io_apic) => {
+            io_apic.set_redirection(gsi, entry);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether the local APIC timer supports `TimerMode::TscDeadline`
+#[allow(unused_unsafe)]
+pub fn tsc_deadline_supported() -> bool {
+    // CPUID.01H:ECX.TSC_Deadline[bit 24]
+    unsafe { __cpuid(1).ecx & (1 << 24) != 0 }
+}
+
+/// Whether the local APIC timer keeps running in deep C-states
+#[allow(unused_unsafe)]
+pub fn timer_always_running() -> bool {
+    // CPUID.06H:EAX.ARAT[bit 2]
+    unsafe { __cpuid(0).eax >= 6 && __cpuid(6).eax & (1 << 2) != 0 }
+}
+
+/// Stop the local APIC timer and make it interrupt at `vector` in `mode`
+///
+/// In one-shot mode the timer counts at the bus frequency divided by 16
+pub fn set_timer_mode(vector: u8, mode: TimerMode) {
+    let local_apic = local_apic();
+    let mode = match mode {
+        TimerMode::OneShot => TIMER_ONE_SHOT,
+        TimerMode::TscDeadline => TIMER_TSC_DEADLINE,
+    };
+
+    local_apic.write(LAPIC_TIMER_INITIAL_COUNT, 0u32);
+    local_apic.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    local_apic.write(LAPIC_TIMER, mode | u32::from(vector));
+    // The mode change disarms a pending deadline
+    if mode == TIMER_TSC_DEADLINE {
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(0) };
+    }
+}
+
+/// Start counting down from `count` in one-shot mode, or stop the timer if `count` is 0
+pub fn start_timer(count: u32) {
+    local_apic().write(LAPIC_TIMER_INITIAL_COUNT, count);
+}
+
+/// The remaining count of the timer in one-shot mode
+pub fn timer_count() -> u32 {
+    local_apic().read(LAPIC_TIMER_CURRENT_COUNT)
+}
+
+/// Interrupt once the TSC reaches `deadline` in TSC-deadline mode, or disarm the timer if it's 0
+///
+/// A deadline in the past interrupts immediately
+pub fn set_tsc_deadline(deadline: u64) {
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) };
+}
+
+/// Signal the end of an interrupt to the local APIC
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = LOCAL_APIC.get() {
+        local_apic.write(LAPIC_EOI, 0u32);
+    }
+}
+
+fn local_apic() -> &'static IoMemory {
+    LOCAL_APIC.get().expect("APIC not initialized")
+}
@@ -0,0 +1,319 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::{
+    instructions::hlt,
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+use crate::memory::{self, Region};
+
+use super::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX, PAGE_FAULT_IST_INDEX};
+
+/// Whether the page fault handler is running
+///
+/// Every page fault starts at the top of the same stack, so a fault while handling one overwrites
+/// the stack frame of the fault being handled, which can't be returned to anymore
+static HANDLING_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+
+/// Install the CPU exception handlers
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(non_maskable_interrupt_handler)
+            .set_stack_index(NMI_IST_INDEX as u16)
+    };
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16)
+    };
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(page_fault_handler)
+            .set_stack_index(PAGE_FAULT_IST_INDEX as u16)
+    };
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    unsafe {
+        idt.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(MACHINE_CHECK_IST_INDEX as u16)
+    };
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+}
+
+/// Install the handlers of page faults and double faults, on the current stack since the exception
+/// stacks don't exist yet
+pub fn set_early_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.double_fault.set_handler_fn(double_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+}
+
+/// Print the crash report of a fatal exception and halt
+///
+/// Exception specific details should be printed before calling this function
+fn crash(stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+    if let Some(error_code) = error_code {
+        println!("  Error code: {:#x}", error_code);
+    }
+
+    println!("  Registers:");
+    println!(
+        "    RIP: {:#x}  CS: {:#x}  RFLAGS: {:#x}",
+        stack_frame.instruction_pointer, stack_frame.code_segment, stack_frame.cpu_flags
+    );
+    println!(
+        "    RSP: {:#x}  SS: {:#x}",
+        stack_frame.stack_pointer, stack_frame.stack_segment
+    );
+    println!("    CR0: {:#x}  CR2: {:#x}", Cr0::read_raw(), Cr2::read());
+    println!(
+        "    CR3: {:#x}  CR4: {:#x}",
+        Cr3::read().0.start_address(),
+        Cr4::read_raw()
+    );
+
+    loop {
+        hlt();
+    }
+}
+
+/// Report a stack overflow if `address` is in the guard page of a known stack
+///
+/// Returns whether a stack overflow was detected
+fn detect_stack_overflow(address: VirtAddr) -> bool {
+    let stack_name = match memory::region_of(address) {
+        Some(Region::P4GuardPage) => {
+            // The boot P3 and P2 tables between the stack and the guard page aren't used anymore,
+            // so the overflow only faults once it ran through them
+            println!(
+                "  Stack overflow: the boot stack overflowed through the boot page tables into the \
+                 guard page"
+            );
+            return true;
+        }
+        Some(Region::StackGuardPage(stack)) => stack.name(),
+        _ => return false,
+    };
+
+    println!(
+        "  Stack overflow: the {} stack overflowed into its guard page",
+        stack_name
+    );
+
+    true
+}
+
+/// Print the segment selector referenced by a selector error code
+fn print_selector(error_code: u64) {
+    if error_code == 0 {
+        println!("  Not caused by a segment selector");
+        return;
+    }
+
+    let table = match (error_code >> 1) & 0b11 {
+        0b00 => "GDT",
+        0b10 => "LDT",
+        _ => "IDT",
+    };
+    println!(
+        "  Selector: index {} in the {}{}",
+        (error_code >> 3) & 0x1fff,
+        table,
+        if error_code & 1 != 0 {
+            ", caused by an external event"
+        } else {
+            ""
+        }
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Divide error");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Debug");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Non-maskable interrupt");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Breakpoint");
+    println!("  Stack frame: {:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Overflow");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Bound range exceeded");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Invalid opcode");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Device not available");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    println!("Exception: Double fault");
+    // A double fault is usually caused by a page fault that couldn't be handled, so check whether
+    // the last page fault was a stack overflow
+    detect_stack_overflow(Cr2::read());
+    crash(&stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    println!("Exception: Invalid TSS");
+    print_selector(error_code);
+    crash(&stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    println!("Exception: Segment not present");
+    print_selector(error_code);
+    crash(&stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    println!("Exception: Stack-segment fault");
+    print_selector(error_code);
+    crash(&stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    println!("Exception: General protection fault");
+    print_selector(error_code);
+    crash(&stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let address = Cr2::read();
+
+    if HANDLING_PAGE_FAULT.swap(true, Ordering::SeqCst) {
+        println!("Exception: Page fault while handling a page fault");
+        println!("  Accessed address: {:#x}", address);
+        crash(&stack_frame, Some(error_code.bits()));
+    }
+
+    // Pages of demand paged regions are mapped when they are first accessed
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && memory::map_on_demand(address)
+    {
+        HANDLING_PAGE_FAULT.store(false, Ordering::SeqCst);
+        return;
+    }
+    // Copy-on-write pages are copied when they are written to
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && memory::copy_on_write(address)
+    {
+        HANDLING_PAGE_FAULT.store(false, Ordering::SeqCst);
+        return;
+    }
+
+    println!("Exception: Page fault");
+    println!("  Accessed address: {:#x}", address);
+    println!(
+        "  Cause: {} {} in {} mode",
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to"
+        } else {
+            "read from"
+        },
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "a protected page"
+        } else {
+            "a non-present page"
+        },
+        if error_code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        }
+    );
+    if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        println!("  A page table entry has a reserved bit set");
+    }
+
+    if !detect_stack_overflow(address) {
+        match memory::region_of(address) {
+            Some(Region::Heap) => println!("  The address is in the kernel heap"),
+            _ => println!("  The address is not in a known kernel region"),
+        }
+    }
+
+    crash(&stack_frame, Some(error_code.bits()));
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    println!("Exception: Alignment check");
+    crash(&stack_frame, Some(error_code));
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    println!("Exception: Machine check");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: SIMD floating point");
+    crash(&stack_frame, None);
+}
+
+extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+    println!("Exception: Virtualization");
+    crash(&stack_frame, None);
+}
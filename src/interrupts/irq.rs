@@ -0,0 +1,309 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{boxed::Box, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, port::Port},
+    structures::idt::{HandlerFunc, InterruptStackFrame},
+};
+
+use super::{apic, PICS, PIC_1_OFFSET};
+
+/// The amount of ISA interrupt lines, which are delivered to the vectors from `PIC_1_OFFSET`
+pub const IRQ_LINES: usize = 16;
+
+/// The line connecting the secondary PIC to the primary one
+const CASCADE_IRQ: u8 = 2;
+/// The lowest priority lines of the PICs, which they raise for spurious interrupts
+const PRIMARY_SPURIOUS_IRQ: u8 = 7;
+const SECONDARY_SPURIOUS_IRQ: u8 = 15;
+
+/// The command ports of the PICs
+const PRIMARY_COMMAND_PORT: u16 = 0x20;
+const SECONDARY_COMMAND_PORT: u16 = 0xa0;
+/// The command selecting the in-service register for the next read of the command port
+const READ_IN_SERVICE: u8 = 0x0b;
+
+/// The entry points of the ISA interrupts, which run the registered handlers
+pub const HANDLERS: [HandlerFunc; IRQ_LINES] = [
+    irq_handler::<0>,
+    irq_handler::<1>,
+    irq_handler::<2>,
+    irq_handler::<3>,
+    irq_handler::<4>,
+    irq_handler::<5>,
+    irq_handler::<6>,
+    irq_handler::<7>,
+    irq_handler::<8>,
+    irq_handler::<9>,
+    irq_handler::<10>,
+    irq_handler::<11>,
+    irq_handler::<12>,
+    irq_handler::<13>,
+    irq_handler::<14>,
+    irq_handler::<15>,
+];
+
+const EMPTY_LINE: Line = Line {
+    handlers: Vec::new(),
+    count: 0,
+    unhandled: 0,
+    spurious: 0,
+};
+
+static LINES: Mutex<[Line; IRQ_LINES]> = Mutex::new([EMPTY_LINE; IRQ_LINES]);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a registered handler, to unregister it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    irq: u8,
+    id: u64,
+}
+
+/// The handlers and counters of an interrupt line
+struct Line {
+    handlers: Vec<Handler>,
+    /// How often the interrupt was raised
+    count: u64,
+    /// How often no handler recognized the interrupt
+    unhandled: u64,
+    /// How often a PIC raised the line without an interrupt in service
+    spurious: u64,
+}
+
+type HandlerFunction = Box<dyn FnMut() -> bool + Send>;
+
+struct Handler {
+    id: u64,
+    name: &'static str,
+    /// Handles the interrupt, and returns whether its device raised it
+    ///
+    /// It is taken out while it runs, so the lines aren't locked meanwhile
+    function: Option<HandlerFunction>,
+}
+
+/// The counters and handler names of an interrupt line
+#[derive(Debug, Clone)]
+pub struct IrqStatistics {
+    pub irq: u8,
+    pub count: u64,
+    pub unhandled: u64,
+    pub spurious: u64,
+    pub handlers: Vec<&'static str>,
+}
+
+/// Mask all lines, until a handler is registered for them
+pub fn init() {
+    if !apic::is_enabled() {
+        let mask = !(1 << CASCADE_IRQ);
+        unsafe { PICS.lock().write_masks(mask, 0xff) };
+    }
+}
+
+/// Run `handler` whenever `irq` is raised, and unmask the line if it was masked
+///
+/// Handlers can share a line, each returns whether its device raised the interrupt. They run with
+/// interrupts disabled. The end of the interrupt is signaled once all handlers of the line ran.
+pub fn register<F: FnMut() -> bool + Send + 'static>(
+    irq: u8,
+    name: &'static str,
+    handler: F,
+) -> HandlerId {
+    assert!((irq as usize) < IRQ_LINES, "IRQ {} doesn't exist", irq);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    interrupts::without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let handlers = &mut lines[irq as usize].handlers;
+        handlers.push(Handler {
+            id,
+            name,
+            function: Some(Box::new(handler)),
+        });
+
+        if handlers.len() == 1 {
+            unmask(irq);
+        }
+    });
+
+    HandlerId { irq, id }
+}
+
+/// Stop running a handler, and mask its line if it was the last one
+///
+/// Returns `false` if the handler was already unregistered
+pub fn unregister(handler: HandlerId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let handlers = &mut lines[handler.irq as usize].handlers;
+        let position = match handlers.iter().position(|entry| entry.id == handler.id) {
+            Some(position) => position,
+            None => return false,
+        };
+        handlers.remove(position);
+
+        if handlers.is_empty() {
+            mask(handler.irq);
+        }
+        true
+    })
+}
+
+/// The counters of all lines
+pub fn statistics() -> Vec<IrqStatistics> {
+    interrupts::without_interrupts(|| {
+        LINES
+            .lock()
+            .iter()
+            .enumerate()
+            .map(|(irq, line)| IrqStatistics {
+                irq: irq as u8,
+                count: line.count,
+                unhandled: line.unhandled,
+                spurious: line.spurious,
+                handlers: line.handlers.iter().map(|handler| handler.name).collect(),
+            })
+            .collect()
+    })
+}
+
+extern "x86-interrupt" fn irq_handler<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    if is_spurious(IRQ) {
+        LINES.lock()[IRQ as usize].spurious += 1;
+        // The secondary PIC did raise the cascade line of the primary one, which needs an EOI
+        if IRQ == SECONDARY_SPURIOUS_IRQ {
+            unsafe {
+                PICS.lock()
+                    .notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ)
+            };
+        }
+        return;
+    }
+
+    // Every handler runs, since several devices may have raised a shared interrupt. The lines
+    // aren't locked while a handler runs, so it can register and unregister handlers. Handlers run
+    // in the order of their IDs, which stays valid when the list changes meanwhile.
+    let mut ran = false;
+    let mut handled = false;
+    let mut last_id = None;
+    while let Some((id, mut function)) = take_next_handler(IRQ, last_id) {
+        handled |= function();
+        put_back_handler(IRQ, id, function);
+        ran = true;
+        last_id = Some(id);
+    }
+
+    {
+        let mut lines = LINES.lock();
+        let line = &mut lines[IRQ as usize];
+        line.count += 1;
+        if !handled {
+            line.unhandled += 1;
+        }
+        // Nobody handles the interrupt, so it would only keep coming
+        if !ran && line.handlers.is_empty() {
+            mask(IRQ);
+        }
+    }
+
+    end_of_interrupt(IRQ);
+}
+
+/// Take the function of the first handler of `irq` whose ID is above `last_id`
+fn take_next_handler(irq: u8, last_id: Option<u64>) -> Option<(u64, HandlerFunction)> {
+    LINES.lock()[irq as usize]
+        .handlers
+        .iter_mut()
+        .filter(|handler| Some(handler.id) > last_id)
+        .find_map(|handler| Some((handler.id, handler.function.take()?)))
+}
+
+/// Return the function of a handler after it ran
+fn put_back_handler(irq: u8, id: u64, function: HandlerFunction) {
+    let function = {
+        let mut lines = LINES.lock();
+        match lines[irq as usize]
+            .handlers
+            .iter_mut()
+            .find(|handler| handler.id == id)
+        {
+            Some(handler) => {
+                handler.function = Some(function);
+                return;
+            }
+            None => function,
+        }
+    };
+
+    // The handler was unregistered while it ran, so it is dropped, but not with the lines locked
+    drop(function);
+}
+
+/// Whether a PIC raised its lowest priority line without an interrupt in service
+///
+/// This happens when an interrupt disappears before the CPU acknowledges it, and such spurious
+/// interrupts must not be acknowledged. With the APIC, the PICs raise them at other vectors.
+fn is_spurious(irq: u8) -> bool {
+    if apic::is_enabled() || (irq != PRIMARY_SPURIOUS_IRQ && irq != SECONDARY_SPURIOUS_IRQ) {
+        return false;
+    }
+
+    in_service() & (1 << irq) == 0
+}
+
+/// Read the in-service registers of both PICs, with a bit for each line
+fn in_service() -> u16 {
+    // The PICs are locked so nothing else uses their command ports meanwhile
+    let _pics = PICS.lock();
+    let mut primary = Port::<u8>::new(PRIMARY_COMMAND_PORT);
+    let mut secondary = Port::<u8>::new(SECONDARY_COMMAND_PORT);
+
+    unsafe {
+        primary.write(READ_IN_SERVICE);
+        secondary.write(READ_IN_SERVICE);
+        u16::from(primary.read()) | (u16::from(secondary.read()) << 8)
+    }
+}
+
+/// Signal the end of an interrupt to the interrupt controller that delivered it
+fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) };
+    }
+}
+
+fn unmask(irq: u8) {
+    if apic::is_enabled() {
+        apic::route_isa_irq(irq, PIC_1_OFFSET + irq);
+        return;
+    }
+
+    let mut pics = PICS.lock();
+    let [primary, secondary] = unsafe { pics.read_masks() };
+    let (primary, secondary) = if irq < 8 {
+        (primary & !(1 << irq), secondary)
+    } else {
+        (primary, secondary & !(1 << (irq - 8)))
+    };
+    unsafe { pics.write_masks(primary, secondary) };
+}
+
+fn mask(irq: u8) {
+    if apic::is_enabled() {
+        apic::mask_isa_irq(irq);
+        return;
+    }
+
+    let mut pics = PICS.lock();
+    let [primary, secondary] = unsafe { pics.read_masks() };
+    let (primary, secondary) = if irq < 8 {
+        (primary | (1 << irq), secondary)
+    } else {
+        (primary, secondary | (1 << (irq - 8)))
+    };
+    unsafe { pics.write_masks(primary, secondary) };
+}
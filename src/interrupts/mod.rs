@@ -0,0 +1,180 @@
+pub mod apic;
+mod exceptions;
+mod irq;
+
+use pc_keyboard::{DecodedKey, HandleControl, Keyboard, layouts, ScancodeSet1};
+use pic8259::ChainedPics;
+use spin::{Lazy, Mutex, Once};
+use x86_64::{
+    instructions::{port::Port, tables::load_tss},
+    registers::segmentation::{CS, Segment},
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        idt::{InterruptDescriptorTable, InterruptStackFrame},
+        tss::TaskStateSegment,
+    },
+};
+
+use crate::memory::MemoryController;
+use crate::shell;
+use crate::time;
+
+pub use self::irq::{
+    register as register_irq, statistics as irq_statistics, unregister as unregister_irq,
+};
+
+const PIC_1_OFFSET: u8 = 32;
+const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+/// Where the PICs are moved when the APIC is used, away from the vectors of the I/O APIC lines, so
+/// the spurious interrupts the masked PICs can still raise aren't taken for ISA interrupts
+const DISABLED_PIC_OFFSET: u8 = 0xe0;
+
+/// The ISA interrupt of the PS/2 keyboard
+const KEYBOARD_IRQ: u8 = 1;
+
+/// The vector of the local APIC and HPET timer interrupts
+pub const TIMER_VECTOR: u8 = PIC_1_OFFSET + irq::IRQ_LINES as u8;
+
+static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+static KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(Keyboard::new(
+    ScancodeSet1::new(),
+    layouts::Us104Key,
+    HandleControl::Ignore,
+));
+
+const DOUBLE_FAULT_IST_INDEX: usize = 0;
+const NMI_IST_INDEX: usize = 1;
+const MACHINE_CHECK_IST_INDEX: usize = 2;
+const PAGE_FAULT_IST_INDEX: usize = 3;
+
+static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    exceptions::set_handlers(&mut idt);
+    for (irq, &handler) in irq::HANDLERS.iter().enumerate() {
+        idt[PIC_1_OFFSET as usize + irq].set_handler_fn(handler);
+    }
+    idt[TIMER_VECTOR as usize].set_handler_fn(timer_interrupt_handler);
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+    for vector in DISABLED_PIC_OFFSET..DISABLED_PIC_OFFSET + irq::IRQ_LINES as u8 {
+        idt[vector as usize].set_handler_fn(spurious_interrupt_handler);
+    }
+
+    idt
+});
+
+/// The descriptor tables used until `init` sets up the exception stacks
+static EARLY_GDT: Lazy<(GlobalDescriptorTable, SegmentSelector)> = Lazy::new(|| {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    (gdt, code_selector)
+});
+static EARLY_IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
+    let mut idt = InterruptDescriptorTable::new();
+    exceptions::set_early_handlers(&mut idt);
+    idt
+});
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<GlobalDescriptorTable> = Once::new();
+
+/// Handle page faults before the memory is initialized, since the heap is backed on demand
+///
+/// The boot GDT isn't mapped after the kernel is remapped, so a GDT in the kernel image is loaded.
+/// Exceptions are handled on the current stack until `init` is called.
+pub fn init_early() {
+    EARLY_GDT.0.load();
+    unsafe { CS::set_reg(EARLY_GDT.1) };
+    EARLY_IDT.load();
+}
+
+pub fn init(memory_controller: &mut MemoryController) {
+    let mut allocate_stack = |name| {
+        memory_controller
+            .allocate_stack(name, 2)
+            .unwrap_or_else(|| panic!("Failed to allocate {} stack", name))
+            .top()
+    };
+    let double_fault_stack = allocate_stack("double fault");
+    let nmi_stack = allocate_stack("NMI");
+    let machine_check_stack = allocate_stack("machine check");
+    let page_fault_stack = allocate_stack("page fault");
+
+    let tss = TSS.call_once(|| {
+        let mut tss = TaskStateSegment::new();
+        // Each of these exceptions gets its own stack, so they can be handled even when the
+        // current stack is corrupted or overflowed
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = double_fault_stack;
+        tss.interrupt_stack_table[NMI_IST_INDEX] = nmi_stack;
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX] = machine_check_stack;
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = page_fault_stack;
+        tss
+    });
+
+    let mut code_selector = SegmentSelector(0);
+    let mut tss_selector = SegmentSelector(0);
+    let gdt = GDT.call_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
+        code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        gdt
+    });
+    gdt.load();
+
+    unsafe {
+        // Reload code segment register
+        CS::set_reg(code_selector);
+        // Load TSS
+        load_tss(tss_selector);
+    }
+
+    IDT.load();
+
+    unsafe {
+        // Remap the PICs even when they aren't used, so their spurious interrupts don't look like
+        // exceptions
+        PICS.lock().initialize();
+    }
+    if apic::init(memory_controller) {
+        let mut pics = PICS.lock();
+        unsafe {
+            *pics = ChainedPics::new(DISABLED_PIC_OFFSET, DISABLED_PIC_OFFSET + 8);
+            pics.initialize();
+            pics.disable();
+        }
+        println!("Interrupts: using the APIC");
+    } else {
+        println!("Interrupts: using the legacy PICs");
+    }
+    irq::init();
+
+    register_irq(KEYBOARD_IRQ, "keyboard", keyboard_interrupt);
+
+    x86_64::instructions::interrupts::enable();
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    time::tick();
+    apic::end_of_interrupt();
+}
+
+fn keyboard_interrupt() -> bool {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    let mut keyboard = KEYBOARD.lock();
+
+    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        if let Some(key) = keyboard.process_keyevent(key_event) {
+            if let DecodedKey::Unicode(character) = key {
+                shell::push_character(character);
+            }
+        }
+    }
+    true
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Spurious interrupts must not be acknowledged
+}
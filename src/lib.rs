@@ -1,6 +1,7 @@
 #![no_std]
 #![feature(panic_info_message)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 extern crate linked_list_allocator;
@@ -12,31 +13,63 @@ extern crate x86_64;
 
 #[macro_use]
 mod vga_buffer;
+mod acpi;
 mod disk;
 mod interrupts;
 mod memory;
+mod shell;
+mod time;
 
-use core::panic::PanicInfo;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::x86_64::__cpuid_count,
+    ops::Deref,
+    panic::PanicInfo,
+};
 
 use alloc::string::String;
 use linked_list_allocator::LockedHeap;
 use multiboot2::{BootInformation, BootInformationHeader};
 use spin::Once;
 use x86_64::{
-    instructions::hlt,
+    instructions::{hlt, interrupts::without_interrupts},
     registers::{
-        control::{Cr0, Cr0Flags},
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
         model_specific::{Efer, EferFlags},
     },
 };
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: KernelHeap = KernelHeap(LockedHeap::empty());
+
+/// The heap allocator, which can also be used by interrupt handlers
+///
+/// Interrupts are disabled while the heap is locked, so a handler never waits for the lock held by
+/// the code it interrupted
+struct KernelHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
+
+impl Deref for KernelHeap {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        &self.0
+    }
+}
 
 static BOOT_INFO: Once<BootInformation> = Once::new();
 
 #[no_mangle]
-extern "C" fn rust_main(multiboot_info_address: usize) {
+extern "C" fn rust_main(multiboot_info_address: usize) -> ! {
     // Get the boot information from multiboot
     let boot_info = BOOT_INFO.call_once(|| unsafe {
         multiboot2::BootInformation::load(multiboot_info_address as *const BootInformationHeader)
@@ -51,17 +84,43 @@ extern "C" fn rust_main(multiboot_info_address: usize) {
     unsafe {
         Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
     };
+    // Prevent the kernel from executing or accessing user pages, if supported
+    unsafe {
+        // CPUID.(EAX=07H,ECX=0H):EBX.SMEP[bit 7] and SMAP[bit 20]
+        let features = __cpuid_count(7, 0).ebx;
+        Cr4::update(|flags| {
+            if features & (1 << 7) != 0 {
+                *flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+            }
+            if features & (1 << 20) != 0 {
+                *flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+            }
+        });
+    }
 
-    // Initialize the memory
-    let mut memory_controller = unsafe { memory::init(&boot_info) };
+    // Initialize the memory, which needs the page fault handler
+    interrupts::init_early();
+    let memory_controller = unsafe { memory::init(&boot_info) };
 
-    interrupts::init(&mut memory_controller);
+    acpi::init(&boot_info, &mut memory_controller.lock());
+    interrupts::init(&mut memory_controller.lock());
+    time::init(&mut memory_controller.lock());
 
     println!("{}", disk::FILESYSTEM.lock().info());
 
-    loop {
-        hlt();
-    }
+    shell::run();
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    println!(
+        "\nAllocation error: failed to allocate {} bytes with alignment {}",
+        layout.size(),
+        layout.align()
+    );
+    memory::print_usage();
+
+    panic!("Out of memory");
 }
 
 #[panic_handler]
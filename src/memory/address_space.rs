@@ -0,0 +1,451 @@
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::tlb::{self, Pcid},
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        page::PageRangeInclusive,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PageTableIndex, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{
+    phys_to_virt, AreaFrameAllocator, COPY_ON_WRITE, PHYSICAL_MEMORY_OFFSET, RECURSIVE_INDEX,
+};
+
+/// The first P4 entry of the kernel half of the address space
+const KERNEL_P4_START: usize = 256;
+
+/// Keeps the TLB entries tagged with the new PCID when written to CR3
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// The P4 table of the kernel address space, which new address spaces copy the kernel mappings from
+static KERNEL_P4_FRAME: Once<PhysFrame> = Once::new();
+
+/// Whether process-context identifiers are enabled
+static PCID_ENABLED: Once<bool> = Once::new();
+
+/// How often kernel mappings were removed, which only invalidates them for the active PCID
+static KERNEL_UNMAPS: AtomicUsize = AtomicUsize::new(0);
+
+static PCIDS: Mutex<PcidAllocator> = Mutex::new(PcidAllocator {
+    next: 1,
+    free: Vec::new(),
+});
+
+/// Hands out process-context identifiers, PCID 0 is used by the kernel address space
+///
+/// A reused PCID may still tag entries of its previous address space, so new address spaces flush
+/// them the first time they are switched to
+struct PcidAllocator {
+    next: u16,
+    free: Vec<Pcid>,
+}
+
+impl PcidAllocator {
+    fn allocate(&mut self) -> Option<Pcid> {
+        if let Some(pcid) = self.free.pop() {
+            return Some(pcid);
+        }
+
+        let pcid = Pcid::new(self.next).ok()?;
+        self.next += 1;
+        Some(pcid)
+    }
+
+    fn deallocate(&mut self, pcid: Pcid) {
+        self.free.push(pcid);
+    }
+}
+
+/// Remember the active page table as the kernel address space, and enable PCID when supported
+///
+/// SAFTEY: This function should only be called once, after the kernel was remapped
+pub unsafe fn init() {
+    KERNEL_P4_FRAME.call_once(|| Cr3::read().0);
+
+    // CPUID.01H:ECX.PCID[bit 17]
+    let pcid_supported = __cpuid(1).ecx & (1 << 17) != 0;
+    if pcid_supported {
+        Cr4::update(|flags| *flags |= Cr4Flags::PCID);
+    }
+    PCID_ENABLED.call_once(|| pcid_supported);
+}
+
+/// Record that kernel mappings were removed, so other PCIDs flush them when switched to
+pub fn kernel_mappings_removed() {
+    KERNEL_UNMAPS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Switch back to the kernel address space
+///
+/// SAFTEY: The caller must not use any mappings of the previous address space
+#[allow(dead_code)]
+pub unsafe fn switch_to_kernel() {
+    let frame = *KERNEL_P4_FRAME
+        .get()
+        .expect("Address spaces not initialized");
+    Cr3::write(frame, Cr3Flags::empty());
+}
+
+/// A set of page tables with its own user mappings, sharing the kernel mappings
+///
+/// Only the kernel P4 entries that exist when the address space is created are shared, so kernel
+/// mappings should be created in existing P4 entries
+pub struct AddressSpace {
+    p4_frame: PhysFrame,
+    pcid: Option<Pcid>,
+    /// The `KERNEL_UNMAPS` count when the TLB entries tagged with the PCID were last flushed, or
+    /// `None` if mappings were changed while another address space was active
+    tlb_valid_at: Option<usize>,
+}
+
+impl AddressSpace {
+    /// Create an address space with the kernel mappings and no user mappings
+    pub fn new<A: FrameAllocator<Size4KiB>>(frame_allocator: &mut A) -> Option<AddressSpace> {
+        let kernel_p4_frame = *KERNEL_P4_FRAME
+            .get()
+            .expect("Address spaces not initialized");
+        let p4_frame = frame_allocator.allocate_frame()?;
+
+        let kernel_p4 = unsafe { &*table_pointer(kernel_p4_frame) };
+        let p4 = unsafe { &mut *table_pointer(p4_frame) };
+        p4.zero();
+        for index in KERNEL_P4_START..512 {
+            p4[index] = kernel_p4[index].clone();
+        }
+        p4[RECURSIVE_INDEX].set_addr(
+            p4_frame.start_address(),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+
+        let pcid = if *PCID_ENABLED.get().unwrap_or(&false) {
+            PCIDS.lock().allocate()
+        } else {
+            None
+        };
+
+        Some(AddressSpace {
+            p4_frame,
+            pcid,
+            tlb_valid_at: None,
+        })
+    }
+
+    /// Whether this is the active address space
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.p4_frame
+    }
+
+    /// Make this the active address space
+    ///
+    /// SAFTEY: The caller must not use any mappings of the previous address space
+    #[allow(dead_code)]
+    pub unsafe fn switch(&mut self) {
+        let pcid = match self.pcid {
+            Some(pcid) => pcid,
+            None => return Cr3::write(self.p4_frame, Cr3Flags::empty()),
+        };
+
+        // The entries tagged with the PCID are only kept if they still match the page tables
+        let unmaps = KERNEL_UNMAPS.load(Ordering::SeqCst);
+        let mut value = self.p4_frame.start_address().as_u64() | u64::from(pcid.value());
+        if self.tlb_valid_at == Some(unmaps) {
+            value |= CR3_NO_FLUSH;
+        }
+        asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+        self.tlb_valid_at = Some(unmaps);
+    }
+
+    /// Map a range of user pages to newly allocated frames
+    ///
+    /// `USER_ACCESSIBLE` and `PRESENT` are added to `flags`
+    pub fn map_user_region<A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>>(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user_region(pages), "Not a user region");
+
+        let is_active = self.is_active();
+        self.changing_mappings(is_active);
+        let mut page_table = unsafe { self.page_table() };
+        for page in pages {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let result = unsafe {
+                page_table.map_to(
+                    page,
+                    frame,
+                    flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+                    frame_allocator,
+                )
+            };
+            let flush = match result {
+                Ok(flush) => flush,
+                Err(error) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(error);
+                }
+            };
+            if is_active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmap a range of user pages, and free their frames
+    ///
+    /// SAFTEY: The pages must not be in use
+    pub unsafe fn unmap_user_region<A: FrameDeallocator<Size4KiB>>(
+        &mut self,
+        pages: PageRangeInclusive,
+        frame_allocator: &mut A,
+    ) -> Result<(), UnmapError> {
+        assert!(is_user_region(pages), "Not a user region");
+
+        let is_active = self.is_active();
+        self.changing_mappings(is_active);
+        let mut page_table = self.page_table();
+        for page in pages {
+            let (frame, flush) = page_table.unmap(page)?;
+            if is_active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            frame_allocator.deallocate_frame(frame);
+        }
+
+        Ok(())
+    }
+
+    /// Map a user page to an existing frame that is shared with other mappings, like a page of a
+    /// file
+    ///
+    /// If `flags` contains `WRITABLE`, the page is mapped copy-on-write instead. The frame must
+    /// have been allocated by `frame_allocator`.
+    pub fn map_shared(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        frame_allocator: &mut AreaFrameAllocator,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(
+            is_user_region(Page::range_inclusive(page, page)),
+            "Not a user region"
+        );
+
+        let mut flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+        }
+
+        let is_active = self.is_active();
+        self.changing_mappings(is_active);
+        let mut page_table = unsafe { self.page_table() };
+        let flush = unsafe {
+            page_table.map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                USER_TABLE_FLAGS,
+                frame_allocator,
+            )?
+        };
+        if is_active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        frame_allocator.share(frame);
+
+        Ok(())
+    }
+
+    /// Create an address space with the same user mappings, which shares all user frames until
+    /// they are written to
+    ///
+    /// The writable user pages of both address spaces become copy-on-write
+    pub fn clone_copy_on_write(
+        &mut self,
+        frame_allocator: &mut AreaFrameAllocator,
+    ) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new(frame_allocator)?;
+
+        match self.share_user_pages(&mut child, frame_allocator) {
+            Ok(()) => Some(child),
+            Err(_) => {
+                child.destroy(frame_allocator);
+                None
+            }
+        }
+    }
+
+    /// Map all user pages into `child` as well, making the writable pages copy-on-write
+    fn share_user_pages(
+        &mut self,
+        child: &mut AddressSpace,
+        frame_allocator: &mut AreaFrameAllocator,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let is_active = self.is_active();
+        self.changing_mappings(is_active);
+        let mut child_table = unsafe { child.page_table() };
+
+        let p4 = unsafe { &*table_pointer(self.p4_frame) };
+        for (p4_index, p4_entry) in p4.iter().enumerate().take(KERNEL_P4_START) {
+            let p3_frame = match p4_entry.frame() {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            let p3 = unsafe { &*table_pointer(p3_frame) };
+
+            for (p3_index, p3_entry) in p3.iter().enumerate() {
+                let p2_frame = match p3_entry.frame() {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                let p2 = unsafe { &*table_pointer(p2_frame) };
+
+                for (p2_index, p2_entry) in p2.iter().enumerate() {
+                    let p1_frame = match p2_entry.frame() {
+                        Ok(frame) => frame,
+                        Err(_) => continue,
+                    };
+                    let p1 = unsafe { &mut *table_pointer(p1_frame) };
+
+                    for (p1_index, p1_entry) in p1.iter_mut().enumerate() {
+                        let frame = match p1_entry.frame() {
+                            Ok(frame) => frame,
+                            Err(_) => continue,
+                        };
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(p4_index as u16),
+                            PageTableIndex::new(p3_index as u16),
+                            PageTableIndex::new(p2_index as u16),
+                            PageTableIndex::new(p1_index as u16),
+                        );
+
+                        let mut flags = p1_entry.flags();
+                        if flags.contains(PageTableFlags::WRITABLE) {
+                            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                            p1_entry.set_flags(flags);
+                            if is_active {
+                                tlb::flush(page.start_address());
+                            }
+                        }
+
+                        unsafe {
+                            child_table
+                                .map_to_with_table_flags(
+                                    page,
+                                    frame,
+                                    flags,
+                                    USER_TABLE_FLAGS,
+                                    frame_allocator,
+                                )?
+                                .ignore()
+                        };
+                        frame_allocator.share(frame);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Free all user frames, the page tables mapping them, and the P4 table
+    ///
+    /// The kernel page tables are shared, so they are kept
+    pub fn destroy<A: FrameDeallocator<Size4KiB>>(self, frame_allocator: &mut A) {
+        assert!(!self.is_active(), "Can't destroy the active address space");
+
+        let p4 = unsafe { &*table_pointer(self.p4_frame) };
+        for p4_entry in p4.iter().take(KERNEL_P4_START) {
+            let p3_frame = match p4_entry.frame() {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            let p3 = unsafe { &*table_pointer(p3_frame) };
+
+            for p3_entry in p3.iter() {
+                let p2_frame = match p3_entry.frame() {
+                    Ok(frame) => frame,
+                    // Unused or a huge page, which aren't used for user regions
+                    Err(_) => continue,
+                };
+                let p2 = unsafe { &*table_pointer(p2_frame) };
+
+                for p2_entry in p2.iter() {
+                    let p1_frame = match p2_entry.frame() {
+                        Ok(frame) => frame,
+                        Err(_) => continue,
+                    };
+                    let p1 = unsafe { &*table_pointer(p1_frame) };
+
+                    for p1_entry in p1.iter() {
+                        if let Ok(frame) = p1_entry.frame() {
+                            unsafe { frame_allocator.deallocate_frame(frame) };
+                        }
+                    }
+                    unsafe { frame_allocator.deallocate_frame(p1_frame) };
+                }
+                unsafe { frame_allocator.deallocate_frame(p2_frame) };
+            }
+            unsafe { frame_allocator.deallocate_frame(p3_frame) };
+        }
+        unsafe { frame_allocator.deallocate_frame(self.p4_frame) };
+
+        if let Some(pcid) = self.pcid {
+            PCIDS.lock().deallocate(pcid);
+        }
+    }
+
+    /// Called before changing the mappings, which are only flushed from the TLB if the address
+    /// space is active, so the entries of an inactive one are flushed when switching to it
+    fn changing_mappings(&mut self, is_active: bool) {
+        if !is_active {
+            self.tlb_valid_at = None;
+        }
+    }
+
+    /// Access the page tables through the physical memory map, which works whether or not the
+    /// address space is active
+    unsafe fn page_table(&mut self) -> OffsetPageTable<'_> {
+        OffsetPageTable::new(
+            &mut *table_pointer(self.p4_frame),
+            VirtAddr::new(PHYSICAL_MEMORY_OFFSET),
+        )
+    }
+}
+
+/// The flags of page tables mapping user pages, which allow copy-on-write pages to become writable
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Get a pointer to a page table through the physical memory map
+fn table_pointer(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Whether the pages are in the user half of the address space
+fn is_user_region(pages: PageRangeInclusive) -> bool {
+    usize::from(pages.end.p4_index()) < KERNEL_P4_START
+}
@@ -1,9 +1,12 @@
+use alloc::collections::BTreeMap;
 use multiboot2::MemoryArea;
 use x86_64::{
-    structures::paging::{FrameAllocator, PageSize, PhysFrame, Size4KiB},
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
+use super::{is_available, phys_to_virt};
+
 pub struct AreaFrameAllocator<'a> {
     next_free_frame: PhysFrame,
     current_area: Option<&'a MemoryArea>,
@@ -12,6 +15,17 @@ pub struct AreaFrameAllocator<'a> {
     kernel_end: PhysFrame,
     multiboot_start: PhysFrame,
     multiboot_end: PhysFrame,
+    allocated_frames: usize,
+    /// The first of the deallocated frames, which are handed out before any new frame
+    ///
+    /// Each free frame holds the address of the next one, so freeing a frame doesn't allocate on
+    /// the heap, which the page fault handler can't do since the heap is demand paged
+    free_frames: Option<PhysFrame>,
+    /// The reference counts of frames that were shared, other allocated frames have one reference
+    ///
+    /// Entries are only removed when their frame is freed, so dropping a reference doesn't free
+    /// heap memory either
+    shared_frames: BTreeMap<PhysFrame, usize>,
 }
 
 impl AreaFrameAllocator<'_> {
@@ -32,25 +46,52 @@ impl AreaFrameAllocator<'_> {
             kernel_end: PhysFrame::containing_address(kernel_end),
             multiboot_start: PhysFrame::containing_address(multiboot_start),
             multiboot_end: PhysFrame::containing_address(multiboot_end),
+            allocated_frames: 0,
+            free_frames: None,
+            shared_frames: BTreeMap::new(),
         };
         allocator.choose_next_area();
 
         allocator
     }
 
+    /// The amount of frames handed out by the allocator
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated_frames
+    }
+
+    /// The amount of frames in all available memory areas
+    pub fn total_frames(&self) -> usize {
+        self.areas
+            .iter()
+            .filter_map(usable_frames)
+            .map(|(first, last)| (last - first + 1) as usize)
+            .sum()
+    }
+
+    /// Add a reference to an allocated frame, so it is only freed when every reference was
+    /// deallocated
+    pub fn share(&mut self, frame: PhysFrame) {
+        *self.shared_frames.entry(frame).or_insert(1) += 1;
+    }
+
+    /// The amount of references to an allocated frame
+    pub fn reference_count(&self, frame: PhysFrame) -> usize {
+        self.shared_frames.get(&frame).cloned().unwrap_or(1)
+    }
+
     fn choose_next_area(&mut self) {
         self.current_area = self
             .areas
             .iter()
-            .filter(|area| {
-                PhysFrame::containing_address(PhysAddr::new(area.end_address() - 1))
-                    >= self.next_free_frame
+            .filter(|area| match usable_frames(area) {
+                Some((_, last)) => last >= self.next_free_frame,
+                None => false,
             })
             .min_by_key(|area| area.start_address());
 
         if let Some(current_area) = self.current_area {
-            let start_frame =
-                PhysFrame::containing_address(PhysAddr::new(current_area.start_address()));
+            let (start_frame, _) = usable_frames(current_area).unwrap();
 
             if self.next_free_frame < start_frame {
                 self.next_free_frame = start_frame;
@@ -61,12 +102,22 @@ impl AreaFrameAllocator<'_> {
 
 unsafe impl FrameAllocator<Size4KiB> for AreaFrameAllocator<'_> {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if let Some(frame) = self.free_frames {
+            // Frame 0 is never allocated, so it ends the list
+            let next: u64 = unsafe { *phys_to_virt(frame.start_address()).as_ptr() };
+            self.free_frames = match next {
+                0 => None,
+                next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+            };
+            self.allocated_frames += 1;
+            return Some(frame);
+        }
+
         if let Some(area) = self.current_area {
             let frame = self.next_free_frame.clone();
 
             // the last frame of the current area
-            let current_area_last_frame =
-                PhysFrame::containing_address(PhysAddr::new(area.end_address() - 1));
+            let (_, current_area_last_frame) = usable_frames(area).unwrap();
 
             if frame > current_area_last_frame {
                 // all frames of current area are used, switch to next area
@@ -88,6 +139,7 @@ unsafe impl FrameAllocator<Size4KiB> for AreaFrameAllocator<'_> {
                     self.next_free_frame.start_address() + Size4KiB::SIZE,
                 )
                 .unwrap();
+                self.allocated_frames += 1;
                 return Some(frame);
             }
             // `frame` was not valid, try it again with the updated `next_free_frame`
@@ -97,3 +149,42 @@ unsafe impl FrameAllocator<Size4KiB> for AreaFrameAllocator<'_> {
         }
     }
 }
+
+impl FrameDeallocator<Size4KiB> for AreaFrameAllocator<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        // Shared frames are only freed when the last reference is dropped
+        match self.shared_frames.get_mut(&frame) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                return;
+            }
+            Some(_) => {
+                self.shared_frames.remove(&frame);
+            }
+            None => {}
+        }
+
+        let next = self
+            .free_frames
+            .map_or(0, |next| next.start_address().as_u64());
+        *phys_to_virt(frame.start_address()).as_mut_ptr() = next;
+        self.free_frames = Some(frame);
+        self.allocated_frames -= 1;
+    }
+}
+
+/// The first and last whole frame of an available memory area
+///
+/// Only these frames are in the physical memory map, which the allocator uses for free frames
+fn usable_frames(area: &MemoryArea) -> Option<(PhysFrame, PhysFrame)> {
+    let start = PhysAddr::new(area.start_address()).align_up(Size4KiB::SIZE);
+    let end = PhysAddr::new(area.end_address()).align_down(Size4KiB::SIZE);
+    if !is_available(area) || start >= end {
+        return None;
+    }
+
+    Some((
+        PhysFrame::containing_address(start),
+        PhysFrame::containing_address(end - 1u64),
+    ))
+}
@@ -0,0 +1,196 @@
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use super::{phys_to_virt, RECURSIVE_INDEX};
+
+/// The flags that are shown in the audit, other flags don't change the access permissions
+const REPORTED_FLAGS: PageTableFlags = PageTableFlags::WRITABLE
+    .union(PageTableFlags::USER_ACCESSIBLE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::GLOBAL)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// Whether the flags allow a page to be written and executed
+pub fn is_writable_and_executable(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+/// Panic if the flags for a new mapping allow it to be written and executed
+pub fn check_flags(flags: PageTableFlags) {
+    assert!(
+        !is_writable_and_executable(flags),
+        "Mappings can't be both writable and executable"
+    );
+}
+
+/// Check every mapping of the active page table, and panic if a page is both writable and
+/// executable
+///
+/// Mappings that are writable and executable and a summary are always printed. If `verbose` is
+/// set, every mapping is printed. Pages with the same flags are reported as one mapping.
+pub fn audit_page_table(verbose: bool) {
+    if verbose {
+        println!("Kernel page table:");
+    }
+
+    let mut auditor = Auditor {
+        mapping: None,
+        mappings: 0,
+        violations: 0,
+        verbose,
+    };
+    let p4 = unsafe { &*table_pointer(Cr3::read().0) };
+    for (p4_index, p4_entry) in p4.iter().enumerate() {
+        // The recursive entry maps the page tables themselves
+        if p4_index == RECURSIVE_INDEX || p4_entry.is_unused() {
+            continue;
+        }
+        let p4_flags = effective_flags(permissive_flags(), p4_entry.flags());
+        let p3 = unsafe { &*table_pointer(p4_entry.frame().unwrap()) };
+
+        for (p3_index, p3_entry) in p3.iter().enumerate() {
+            if p3_entry.is_unused() {
+                continue;
+            }
+            let p3_flags = effective_flags(p4_flags, p3_entry.flags());
+            if p3_flags.contains(PageTableFlags::HUGE_PAGE) {
+                auditor.add(address(p4_index, p3_index, 0, 0), 1 << 30, p3_flags);
+                continue;
+            }
+            let p2 = unsafe { &*table_pointer(p3_entry.frame().unwrap()) };
+
+            for (p2_index, p2_entry) in p2.iter().enumerate() {
+                if p2_entry.is_unused() {
+                    continue;
+                }
+                let p2_flags = effective_flags(p3_flags, p2_entry.flags());
+                if p2_flags.contains(PageTableFlags::HUGE_PAGE) {
+                    auditor.add(address(p4_index, p3_index, p2_index, 0), 1 << 21, p2_flags);
+                    continue;
+                }
+                let p1 = unsafe { &*table_pointer(p2_entry.frame().unwrap()) };
+
+                for (p1_index, p1_entry) in p1.iter().enumerate() {
+                    if p1_entry.is_unused() {
+                        continue;
+                    }
+                    let flags = effective_flags(p2_flags, p1_entry.flags());
+                    auditor.add(
+                        address(p4_index, p3_index, p2_index, p1_index),
+                        1 << 12,
+                        flags,
+                    );
+                }
+            }
+        }
+    }
+    auditor.finish_mapping();
+
+    println!(
+        "Page table audit: {} mappings, {} writable and executable",
+        auditor.mappings, auditor.violations
+    );
+    if auditor.violations > 0 {
+        panic!(
+            "{} mappings are both writable and executable",
+            auditor.violations
+        );
+    }
+}
+
+/// Merges contiguous pages with the same flags into mappings and reports them
+struct Auditor {
+    /// The start, end and flags of the current mapping
+    mapping: Option<(VirtAddr, VirtAddr, PageTableFlags)>,
+    mappings: usize,
+    violations: usize,
+    /// Whether all mappings are printed, violations are always printed
+    verbose: bool,
+}
+
+impl Auditor {
+    fn add(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) {
+        let flags = flags & REPORTED_FLAGS;
+
+        if let Some((_, ref mut end, mapping_flags)) = self.mapping {
+            if *end == start && mapping_flags == flags {
+                *end = start + size;
+                return;
+            }
+        }
+
+        self.finish_mapping();
+        self.mapping = Some((start, start + size, flags));
+    }
+
+    fn finish_mapping(&mut self) {
+        let (start, end, flags) = match self.mapping.take() {
+            Some(mapping) => mapping,
+            None => return,
+        };
+
+        self.mappings += 1;
+        let violation = is_writable_and_executable(flags);
+        if violation {
+            self.violations += 1;
+            if !self.verbose && self.violations == 1 {
+                println!("Writable and executable mappings:");
+            }
+        } else if !self.verbose {
+            return;
+        }
+        println!(
+            "  {:#018x}-{:#018x} {:?}{}",
+            start,
+            end,
+            flags,
+            if violation {
+                " (writable and executable)"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+/// The flags of a table that doesn't restrict the access to its entries
+fn permissive_flags() -> PageTableFlags {
+    PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+}
+
+/// Combine the flags of an entry with the effective flags of the tables above it
+///
+/// A page is only writable or user accessible if every level allows it, and isn't executable if
+/// any level forbids it
+fn effective_flags(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let mut flags = entry;
+    for &flag in &[PageTableFlags::WRITABLE, PageTableFlags::USER_ACCESSIBLE] {
+        if !parent.contains(flag) {
+            flags.remove(flag);
+        }
+    }
+    if parent.contains(PageTableFlags::NO_EXECUTE) {
+        flags.insert(PageTableFlags::NO_EXECUTE);
+    }
+
+    flags
+}
+
+/// The virtual address of a page from its table indices
+fn address(p4_index: usize, p3_index: usize, p2_index: usize, p1_index: usize) -> VirtAddr {
+    VirtAddr::new_truncate(
+        (p4_index as u64) << 39
+            | (p3_index as u64) << 30
+            | (p2_index as u64) << 21
+            | (p1_index as u64) << 12,
+    )
+}
+
+/// Get a pointer to a page table through the physical memory map
+fn table_pointer(frame: PhysFrame) -> *const PageTable {
+    phys_to_virt(frame.start_address()).as_ptr()
+}
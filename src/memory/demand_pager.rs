@@ -0,0 +1,175 @@
+use core::mem;
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{get_active_page_table, phys_to_virt};
+
+/// How many frames are kept for the page fault handler, which covers the whole heap and its page
+/// tables, so the heap can be backed before the memory controller exists
+const RESERVED_FRAMES: usize = 32;
+
+static DEMAND_PAGER: Mutex<DemandPager> = Mutex::new(DemandPager {
+    heap: None,
+    ranges: Vec::new(),
+    frames: [None; RESERVED_FRAMES],
+});
+
+/// A range of pages that are backed by zeroed frames when they are first accessed
+#[derive(Debug, Clone, Copy)]
+struct DemandRange {
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+impl DemandRange {
+    fn contains(&self, address: VirtAddr) -> bool {
+        address >= self.start && address < self.end
+    }
+}
+
+/// What the page fault handler needs to back demand paged memory
+///
+/// It is locked separately from the memory controller, so faults are also served while the
+/// controller is locked. The heap is demand paged too, so nothing is allocated on the heap while
+/// the pager is locked, and it keeps its own frames since the frame allocator uses the heap.
+struct DemandPager {
+    /// The heap is kept apart from the other ranges, since it is backed before they can be
+    /// allocated
+    heap: Option<DemandRange>,
+    ranges: Vec<DemandRange>,
+    frames: [Option<PhysFrame>; RESERVED_FRAMES],
+}
+
+unsafe impl FrameAllocator<Size4KiB> for DemandPager {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.frames.iter_mut().find_map(Option::take)
+    }
+}
+
+/// Back the heap at `start` on demand
+pub fn set_heap(start: VirtAddr, size: u64, flags: PageTableFlags) {
+    interrupts::without_interrupts(|| {
+        DEMAND_PAGER.lock().heap = Some(DemandRange {
+            start,
+            end: start + size,
+            flags,
+        })
+    });
+}
+
+/// Back `size` bytes at `start` on demand
+pub fn add(start: VirtAddr, size: u64, flags: PageTableFlags) {
+    let range = DemandRange {
+        start,
+        end: start + size,
+        flags,
+    };
+
+    // The list is replaced by a copy with room for the range, which is allocated and written
+    // before locking the pager, so its pages are already backed when it is filled
+    let mut ranges = Vec::new();
+    loop {
+        let length = interrupts::without_interrupts(|| DEMAND_PAGER.lock().ranges.len());
+        ranges.resize(length + 1, range);
+
+        let replaced = interrupts::without_interrupts(|| {
+            let mut pager = DEMAND_PAGER.lock();
+            // Another range may have been added meanwhile
+            if pager.ranges.len() != length {
+                return None;
+            }
+            ranges[..length].copy_from_slice(&pager.ranges);
+            Some(mem::replace(
+                &mut pager.ranges,
+                mem::replace(&mut ranges, Vec::new()),
+            ))
+        });
+
+        // The old list is freed after unlocking the pager
+        if replaced.is_some() {
+            return;
+        }
+    }
+}
+
+/// Stop backing the range starting at `start`, which was added with `add`
+///
+/// Pages that were already backed stay mapped. Returns `false` if there is no such range.
+pub fn remove(start: VirtAddr) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut pager = DEMAND_PAGER.lock();
+        match pager.ranges.iter().position(|range| range.start == start) {
+            Some(index) => {
+                // Removing doesn't allocate, so it can be done in place
+                pager.ranges.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Reserve frames from `frame_allocator` until the pager has `RESERVED_FRAMES` of them
+pub fn refill<A: FrameAllocator<Size4KiB>>(frame_allocator: &mut A) {
+    interrupts::without_interrupts(|| {
+        let mut pager = DEMAND_PAGER.lock();
+        for frame in pager.frames.iter_mut().filter(|frame| frame.is_none()) {
+            *frame = frame_allocator.allocate_frame();
+        }
+    });
+}
+
+/// Back a page with a zeroed frame, if it is in a demand paged range
+///
+/// This is called from the page fault handler, which disables interrupts. Returns whether the
+/// page was mapped.
+pub fn map_on_demand(page: Page) -> bool {
+    // The pager is only locked here if it faulted itself, which can't be handled
+    let mut pager = match DEMAND_PAGER.try_lock() {
+        Some(pager) => pager,
+        None => return false,
+    };
+
+    let address = page.start_address();
+    let flags = match pager
+        .heap
+        .iter()
+        .chain(pager.ranges.iter())
+        .find(|range| range.contains(address))
+    {
+        Some(range) => range.flags,
+        None => return false,
+    };
+
+    let frame = match pager.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        let pointer: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        pointer.write_bytes(0, Size4KiB::SIZE as usize);
+    }
+
+    // Page tables needed for the mapping are taken from the reserved frames as well
+    match unsafe { get_active_page_table().map_to(page, frame, flags, &mut *pager) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            if let Some(slot) = pager.frames.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(frame);
+            }
+            false
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use core::arch::x86_64::__cpuid;
+
+use spin::Once;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+        Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Whether the CPU supports 1 GiB pages
+static GIGABYTE_PAGES: Once<bool> = Once::new();
+
+/// Whether the CPU supports 1 GiB pages, 2 MiB pages are always supported in long mode
+#[allow(unused_unsafe)]
+pub fn gigabyte_pages_supported() -> bool {
+    // CPUID.80000001H:EDX.Page1GB[bit 26]
+    *GIGABYTE_PAGES.call_once(|| unsafe { __cpuid(0x8000_0001).edx & (1 << 26) != 0 })
+}
+
+/// The size of the largest page that can be used for a mapping of `size` bytes
+pub fn largest_page_size(size: u64) -> u64 {
+    if gigabyte_pages_supported() && size >= Size1GiB::SIZE {
+        Size1GiB::SIZE
+    } else if size >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Map `size` bytes of contiguous physical memory starting at `frame_start` to `start`, using the
+/// largest pages possible
+///
+/// SAFTEY: The caller must ensure the mapping doesn't alias memory that is in use
+pub unsafe fn map_contiguous<M, A>(
+    page_table: &mut M,
+    start: VirtAddr,
+    frame_start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB>,
+    A: FrameAllocator<Size4KiB>,
+{
+    assert!(start.is_aligned(Size4KiB::SIZE) && frame_start.is_aligned(Size4KiB::SIZE));
+
+    let mut offset = 0;
+    while offset < size {
+        let page_start = start + offset;
+        let frame = frame_start + offset;
+        let remaining = size - offset;
+
+        // Both addresses need the alignment of a page for it to be used
+        let fits = |page_size: u64| {
+            remaining >= page_size
+                && page_start.is_aligned(page_size)
+                && frame.is_aligned(page_size)
+        };
+
+        if gigabyte_pages_supported() && fits(Size1GiB::SIZE) {
+            page_table
+                .map_to(
+                    Page::<Size1GiB>::containing_address(page_start),
+                    PhysFrame::containing_address(frame),
+                    flags,
+                    frame_allocator,
+                )
+                .map_err(into_4kib_error)?
+                .flush();
+            offset += Size1GiB::SIZE;
+        } else if fits(Size2MiB::SIZE) {
+            page_table
+                .map_to(
+                    Page::<Size2MiB>::containing_address(page_start),
+                    PhysFrame::containing_address(frame),
+                    flags,
+                    frame_allocator,
+                )
+                .map_err(into_4kib_error)?
+                .flush();
+            offset += Size2MiB::SIZE;
+        } else {
+            page_table
+                .map_to(
+                    Page::<Size4KiB>::containing_address(page_start),
+                    PhysFrame::containing_address(frame),
+                    flags,
+                    frame_allocator,
+                )?
+                .flush();
+            offset += Size4KiB::SIZE;
+        }
+    }
+
+    Ok(())
+}
+
+/// Unmap `size` bytes starting at `start` that were mapped with `map_contiguous`
+///
+/// The frames are not deallocated
+pub fn unmap_contiguous<M>(page_table: &mut M, start: VirtAddr, size: u64) -> Result<(), UnmapError>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB> + Mapper<Size1GiB> + Translate,
+{
+    let mut offset = 0;
+    while offset < size {
+        let address = start + offset;
+
+        offset += match page_table.translate(address) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                ..
+            } => {
+                Mapper::<Size4KiB>::unmap(page_table, Page::containing_address(address))?
+                    .1
+                    .flush();
+                Size4KiB::SIZE
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                Mapper::<Size2MiB>::unmap(page_table, Page::containing_address(address))?
+                    .1
+                    .flush();
+                Size2MiB::SIZE
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size1GiB(_),
+                ..
+            } => {
+                Mapper::<Size1GiB>::unmap(page_table, Page::containing_address(address))?
+                    .1
+                    .flush();
+                Size1GiB::SIZE
+            }
+            _ => return Err(UnmapError::PageNotMapped),
+        };
+    }
+
+    Ok(())
+}
+
+/// Convert the error of mapping a huge page to the error type of 4 KiB mappings
+fn into_4kib_error<S: PageSize>(error: MapToError<S>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
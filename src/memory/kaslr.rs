@@ -0,0 +1,24 @@
+use core::arch::x86_64::_rdtsc;
+
+use x86_64::instructions::random::RdRand;
+
+/// Get a random number below `bound`, used to randomize the addresses of kernel regions
+pub fn random_below(bound: u64) -> u64 {
+    assert!(bound > 0);
+    random() % bound
+}
+
+/// Get a random number from `RDRAND`, or from the time stamp counter if it isn't supported
+#[allow(unused_unsafe)]
+fn random() -> u64 {
+    if let Some(value) = RdRand::new().and_then(RdRand::get_u64) {
+        return value;
+    }
+
+    // The low bits of the time stamp counter change the most, so mix them into all bits
+    // (splitmix64 finalizer)
+    let mut value = unsafe { _rdtsc() };
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
@@ -0,0 +1,141 @@
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    mem::{align_of, size_of},
+    ptr,
+};
+
+use spin::Once;
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+/// The page attribute table MSR
+const IA32_PAT: u32 = 0x277;
+
+/// The memory type of PAT entry 1, which is write-through by default
+const PAT_WRITE_COMBINING: u64 = 0x01;
+
+/// Whether the page attribute table is supported and was programmed
+static PAT_ENABLED: Once<bool> = Once::new();
+
+/// How accesses to memory mapped I/O are cached
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    /// Every access goes to the device, used for registers
+    Uncached,
+    /// Writes are buffered and combined, used for frame buffers
+    ///
+    /// Falls back to `Uncached` when the CPU doesn't support the page attribute table
+    WriteCombining,
+}
+
+impl CacheType {
+    /// The page table flags selecting the memory type
+    ///
+    /// Only the `PWT` and `PCD` bits are used, since the `PAT` bit is at a different position in
+    /// huge page entries
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheType::WriteCombining if *PAT_ENABLED.get().unwrap_or(&false) => {
+                // PAT entry 1
+                PageTableFlags::WRITE_THROUGH
+            }
+            // PAT entry 3, which is uncached by default
+            _ => PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+/// Make PAT entry 1 write-combining, if the page attribute table is supported
+///
+/// The entry is changed the way the Intel SDM describes, so no cache line or TLB entry keeps the
+/// old memory type
+///
+/// SAFTEY: This function should only be called once, before any page is mapped with only the
+/// `WRITE_THROUGH` flag
+#[allow(unused_unsafe)]
+pub unsafe fn init() {
+    // CPUID.01H:EDX.PAT[bit 16]
+    let pat_supported = unsafe { __cpuid(1).edx } & (1 << 16) != 0;
+    if pat_supported {
+        interrupts::without_interrupts(|| unsafe {
+            // Stop filling the caches, and write back and invalidate their contents
+            let cr0 = Cr0::read();
+            Cr0::write((cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH);
+            asm!("wbinvd", options(nostack, preserves_flags));
+            flush_all_tlbs();
+
+            let mut pat = Msr::new(IA32_PAT);
+            let entries = pat.read();
+            pat.write((entries & !(0xff << 8)) | (PAT_WRITE_COMBINING << 8));
+
+            asm!("wbinvd", options(nostack, preserves_flags));
+            flush_all_tlbs();
+            Cr0::write(cr0);
+        });
+    }
+    PAT_ENABLED.call_once(|| pat_supported);
+}
+
+/// Flush the TLB entries of every PCID, including global ones, since changing `CR4.PGE` flushes
+/// them all
+unsafe fn flush_all_tlbs() {
+    let cr4 = Cr4::read();
+    Cr4::write(cr4 ^ Cr4Flags::PAGE_GLOBAL);
+    Cr4::write(cr4);
+}
+
+/// A mapping of memory mapped I/O, accessed with volatile reads and writes
+#[derive(Debug)]
+pub struct IoMemory {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl IoMemory {
+    pub(super) fn new(start: VirtAddr, size: u64) -> IoMemory {
+        IoMemory { start, size }
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    #[allow(dead_code)]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Read a value at `offset` bytes into the mapping
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { ptr::read_volatile(self.pointer::<T>(offset)) }
+    }
+
+    /// Write a value at `offset` bytes into the mapping
+    ///
+    /// Single writes need no exclusive access, but registers accessed in several steps, like an
+    /// index and a data register, must be locked by their owner
+    pub fn write<T: Copy>(&self, offset: u64, value: T) {
+        unsafe { ptr::write_volatile(self.pointer::<T>(offset), value) }
+    }
+
+    fn pointer<T>(&self, offset: u64) -> *mut T {
+        assert!(
+            offset + size_of::<T>() as u64 <= self.size,
+            "Access outside of the mapping"
+        );
+        assert!(
+            offset % align_of::<T>() as u64 == 0,
+            "Unaligned access to memory mapped I/O"
+        );
+
+        (self.start + offset).as_mut_ptr()
+    }
+}
@@ -1,51 +1,539 @@
+mod address_space;
 mod area_frame_allocator;
+mod audit;
+mod demand_pager;
+mod huge_pages;
+mod kaslr;
+mod mmio;
+mod region_allocator;
 mod stack_allocator;
 
-use multiboot2::{BootInformation, ElfSectionFlags};
+use core::{alloc::Layout, iter};
+
+use alloc::string::String;
+use linked_list_allocator::Heap;
+use multiboot2::{BootInformation, ElfSection, ElfSectionFlags, MemoryArea, MemoryAreaType};
+use spin::{Mutex, Once};
 use x86_64::{
     instructions::tlb,
     registers::control::Cr3,
     structures::paging::{
-        FrameAllocator, Mapper, Page, PageTable, PageTableFlags, PhysFrame, RecursivePageTable,
-        Size4KiB,
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        page::PageRangeInclusive,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, RecursivePageTable, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
+#[cfg(feature = "offset-page-table")]
+use x86_64::structures::paging::OffsetPageTable;
+
 use crate::HEAP_ALLOCATOR;
 
+pub use self::address_space::AddressSpace;
 pub use self::area_frame_allocator::AreaFrameAllocator;
+pub use self::mmio::{CacheType, IoMemory};
+use self::region_allocator::{RegionAllocator, RegionKind};
 use self::stack_allocator::{Stack, StackAllocator};
 
-const HEAP_START: *mut u8 = 0o_000_001_000_000_0000 as *mut u8;
+/// The virtual address the kernel is linked at, relative to its physical address
+pub const KERNEL_OFFSET: u64 = 0xffff_ffff_8000_0000;
+
+/// The virtual memory range that kernel regions (heap, stacks, ...) are allocated from
+const DYNAMIC_AREA_START: u64 = 0o_177777_600_000_000_000_0000;
+const DYNAMIC_AREA_END: u64 = 0o_177777_601_000_000_000_0000;
+
 const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
-const P4: *mut PageTable = 0xffffffff_fffff000 as *mut _;
+/// The size of the virtual memory range used for kernel stacks
+const STACK_AREA_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+/// The virtual address that all physical memory is mapped to
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xffff_8000_0000_0000;
+
+/// The P4 entry used for the recursive mapping, the last entry is used by the kernel
+const RECURSIVE_INDEX: usize = 510;
+const P4: *mut PageTable = 0xffffff7f_bfdfe000 as *mut _;
+
+/// An unused page table entry bit marking read-only pages that are copied when written to
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The page table type used to modify the active page table
+#[cfg(not(feature = "offset-page-table"))]
+pub type ActivePageTable = RecursivePageTable<'static>;
+/// The page table type used to modify the active page table
+#[cfg(feature = "offset-page-table")]
+pub type ActivePageTable = OffsetPageTable<'static>;
+
+#[cfg(not(feature = "offset-page-table"))]
+unsafe fn get_active_page_table() -> ActivePageTable {
+    get_recursive_page_table()
+}
+
+#[cfg(feature = "offset-page-table")]
+unsafe fn get_active_page_table() -> ActivePageTable {
+    let p4 = phys_to_virt(Cr3::read().0.start_address()).as_mut_ptr();
+    OffsetPageTable::new(&mut *p4, VirtAddr::new(PHYSICAL_MEMORY_OFFSET))
+}
 
-unsafe fn get_active_page_table() -> RecursivePageTable<'static> {
+unsafe fn get_recursive_page_table() -> RecursivePageTable<'static> {
     RecursivePageTable::new(&mut *P4).unwrap()
 }
 
+/// Get the physical address of an address in the kernel image or the boot mappings
+fn kernel_virt_to_phys(address: u64) -> PhysAddr {
+    if address >= KERNEL_OFFSET {
+        PhysAddr::new(address - KERNEL_OFFSET)
+    } else {
+        // The boot code is linked at its physical address
+        PhysAddr::new(address)
+    }
+}
+
+/// Get the virtual address that a physical address is mapped to in the physical memory map
+///
+/// The physical memory map is only available after `init` was called, and only covers RAM
+pub fn phys_to_virt(address: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET + address.as_u64())
+}
+
+static MEMORY_CONTROLLER: Once<Mutex<MemoryController>> = Once::new();
+
+/// The guard page created from the original P4 table in `remap_kernel`
+static P4_GUARD_PAGE: Once<Page> = Once::new();
+
+/// A kernel memory region that contains a virtual address
+#[derive(Debug)]
+pub enum Region {
+    /// The guard page created from the original P4 table, which lies below the boot P3 and P2
+    /// tables and the boot stack above them
+    P4GuardPage,
+    /// The guard page of a stack allocated by the `StackAllocator`
+    StackGuardPage(Stack),
+    /// The kernel heap
+    Heap,
+}
+
 pub struct MemoryController {
-    active_page_table: RecursivePageTable<'static>,
+    active_page_table: ActivePageTable,
     frame_allocator: AreaFrameAllocator<'static>,
     stack_allocator: StackAllocator,
+    regions: RegionAllocator,
 }
 
 impl MemoryController {
-    pub fn allocate_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+    pub fn allocate_stack(&mut self, name: &'static str, size_in_pages: usize) -> Option<Stack> {
         self.stack_allocator.allocate_stack(
             &mut self.active_page_table,
             &mut self.frame_allocator,
+            name,
             size_in_pages,
         )
     }
+
+    /// Allocate a stack whose pages are only backed by frames when they are first accessed
+    #[allow(dead_code)]
+    pub fn reserve_stack(&mut self, name: &'static str, size_in_pages: usize) -> Option<Stack> {
+        let stack = self.stack_allocator.reserve_stack(name, size_in_pages)?;
+        // Only the stack is backed, never its guard page
+        demand_pager::add(
+            stack.bottom(),
+            stack.top() - stack.bottom(),
+            PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+        );
+
+        Some(stack)
+    }
+
+    /// Free a stack, so its memory can be used by new stacks
+    ///
+    /// SAFTEY: The stack must not be in use
+    #[allow(dead_code)]
+    pub unsafe fn deallocate_stack(&mut self, stack: Stack) {
+        demand_pager::remove(stack.bottom());
+        self.stack_allocator.deallocate_stack(
+            &mut self.active_page_table,
+            &mut self.frame_allocator,
+            stack,
+        );
+        address_space::kernel_mappings_removed();
+    }
+
+    /// Create an address space that shares the kernel mappings
+    #[allow(dead_code)]
+    pub fn create_address_space(&mut self) -> Option<AddressSpace> {
+        AddressSpace::new(&mut self.frame_allocator)
+    }
+
+    /// Map a range of user pages in an address space to newly allocated frames
+    #[allow(dead_code)]
+    pub fn map_user_region(
+        &mut self,
+        address_space: &mut AddressSpace,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        audit::check_flags(flags);
+        address_space.map_user_region(pages, flags, &mut self.frame_allocator)
+    }
+
+    /// Unmap a range of user pages in an address space, and free their frames
+    ///
+    /// SAFTEY: The pages must not be in use
+    #[allow(dead_code)]
+    pub unsafe fn unmap_user_region(
+        &mut self,
+        address_space: &mut AddressSpace,
+        pages: PageRangeInclusive,
+    ) -> Result<(), UnmapError> {
+        address_space.unmap_user_region(pages, &mut self.frame_allocator)
+    }
+
+    /// Destroy an inactive address space, freeing its user frames and page tables
+    #[allow(dead_code)]
+    pub fn destroy_address_space(&mut self, address_space: AddressSpace) {
+        address_space.destroy(&mut self.frame_allocator)
+    }
+
+    /// Create a copy of an address space that shares its user frames until they are written to
+    #[allow(dead_code)]
+    pub fn clone_address_space(
+        &mut self,
+        address_space: &mut AddressSpace,
+    ) -> Option<AddressSpace> {
+        address_space.clone_copy_on_write(&mut self.frame_allocator)
+    }
+
+    /// Map a user page in an address space to a frame that is shared with other mappings
+    #[allow(dead_code)]
+    pub fn map_shared(
+        &mut self,
+        address_space: &mut AddressSpace,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        // Copy-on-write pages become writable when they are written to
+        audit::check_flags(flags);
+        address_space.map_shared(page, frame, flags, &mut self.frame_allocator)
+    }
+
+    /// Map a range of physical memory into a new kernel region, using huge pages where possible
+    ///
+    /// Returns the virtual address of `start`
+    #[allow(dead_code)]
+    pub fn map_physical_region(
+        &mut self,
+        name: &'static str,
+        start: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Option<VirtAddr> {
+        self.map_physical(name, RegionKind::PhysicalMemory, start, size, flags)
+    }
+
+    /// Unmap a region created by `map_physical_region`
+    ///
+    /// SAFTEY: The region must not be in use
+    #[allow(dead_code)]
+    pub unsafe fn unmap_physical_region(&mut self, address: VirtAddr) {
+        let start = self
+            .regions
+            .find(address)
+            .filter(|region| region.kind() == RegionKind::PhysicalMemory)
+            .expect("Not a physical memory region")
+            .start();
+        self.unmap_physical(start);
+    }
+
+    /// Map a range of memory mapped I/O with the given caching
+    #[allow(dead_code)]
+    pub fn ioremap(
+        &mut self,
+        name: &'static str,
+        start: PhysAddr,
+        size: u64,
+        cache_type: CacheType,
+    ) -> Option<IoMemory> {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache_type.flags();
+        let address = self.map_physical(name, RegionKind::Mmio, start, size, flags)?;
+
+        Some(IoMemory::new(address, size))
+    }
+
+    /// Unmap memory mapped I/O mapped with `ioremap`
+    #[allow(dead_code)]
+    pub fn iounmap(&mut self, io_memory: IoMemory) {
+        let start = self
+            .regions
+            .find(io_memory.start())
+            .expect("Memory mapped I/O not mapped")
+            .start();
+        self.unmap_physical(start);
+    }
+
+    fn map_physical(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        start: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Option<VirtAddr> {
+        audit::check_flags(flags);
+        let frame_start = start.align_down(Size4KiB::SIZE);
+        let size = (start + size).align_up(Size4KiB::SIZE) - frame_start;
+
+        // The virtual address needs the same offset into a huge page as the physical address, so
+        // find an aligned range with room for the offset, and only keep the used part of it
+        let align = huge_pages::largest_page_size(size);
+        let misalignment = frame_start.as_u64() % align;
+        let range_start = self
+            .regions
+            .allocate_aligned(name, kind, misalignment + size, align, flags)?
+            .start();
+        self.regions.free(range_start);
+        let page_start = range_start + misalignment;
+        self.regions.reserve(name, kind, page_start, size, flags)?;
+
+        let result = unsafe {
+            huge_pages::map_contiguous(
+                &mut self.active_page_table,
+                page_start,
+                frame_start,
+                size,
+                flags | PageTableFlags::PRESENT,
+                &mut self.frame_allocator,
+            )
+        };
+        if result.is_err() {
+            self.regions.free(page_start);
+            // Unmap the pages that were mapped before the error
+            let _ = huge_pages::unmap_contiguous(&mut self.active_page_table, page_start, size);
+            return None;
+        }
+
+        Some(page_start + (start - frame_start))
+    }
+
+    fn unmap_physical(&mut self, start: VirtAddr) {
+        let region = self.regions.free(start).unwrap();
+        huge_pages::unmap_contiguous(&mut self.active_page_table, region.start(), region.size())
+            .expect("Failed to unmap physical region");
+        address_space::kernel_mappings_removed();
+    }
+
+    /// Reserve a kernel region whose pages are only backed by frames when they are first accessed
+    ///
+    /// Returns the start address of the region
+    #[allow(dead_code)]
+    pub fn reserve_region(
+        &mut self,
+        name: &'static str,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Option<VirtAddr> {
+        audit::check_flags(flags);
+        let region = self.regions.allocate_demand_paged(
+            name,
+            RegionKind::Anonymous,
+            size,
+            flags | PageTableFlags::PRESENT,
+        )?;
+        demand_pager::add(region.start(), region.size(), region.flags());
+
+        Some(region.start())
+    }
+
+    /// Free a region created by `reserve_region`, unmapping the pages that were accessed
+    ///
+    /// SAFTEY: The region must not be in use
+    #[allow(dead_code)]
+    pub unsafe fn free_region(&mut self, start: VirtAddr) {
+        let region = self.regions.free(start).expect("Region doesn't exist");
+        assert_eq!(
+            region.kind(),
+            RegionKind::Anonymous,
+            "Not a reserved region"
+        );
+        demand_pager::remove(region.start());
+
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(region.start()),
+            Page::containing_address(region.end()),
+        );
+        for page in pages {
+            match self.active_page_table.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    self.frame_allocator.deallocate_frame(frame);
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(error) => panic!("Failed to unmap region page: {:?}", error),
+            }
+        }
+        address_space::kernel_mappings_removed();
+    }
+}
+
+/// Handle a write to a copy-on-write page of the active address space
+///
+/// The page is made writable if no other mapping uses its frame, and is copied to a new frame
+/// otherwise. Returns whether the fault was handled.
+pub fn copy_on_write(address: VirtAddr) -> bool {
+    let mut controller = match MEMORY_CONTROLLER.get().and_then(Mutex::try_lock) {
+        Some(controller) => controller,
+        None => return false,
+    };
+    let frame_allocator = &mut controller.frame_allocator;
+    let page = Page::containing_address(address);
+
+    // The controller's page table may belong to another address space
+    let mut page_table = unsafe { get_active_page_table() };
+    let (frame, flags) = match page_table.translate(address) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return false,
+    };
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    if frame_allocator.reference_count(frame) == 1 {
+        return match unsafe { page_table.update_flags(page, flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    let copy = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        let source: *const u8 = phys_to_virt(frame.start_address()).as_ptr();
+        let destination: *mut u8 = phys_to_virt(copy.start_address()).as_mut_ptr();
+        destination.copy_from_nonoverlapping(source, Size4KiB::SIZE as usize);
+    }
+
+    let (frame, flush) = page_table.unmap(page).unwrap();
+    flush.flush();
+    unsafe {
+        // Drop this mapping's reference to the shared frame, which only decrements its reference
+        // count since it has more than one, so nothing is allocated or freed on the heap
+        frame_allocator.deallocate_frame(frame);
+        page_table
+            .map_to(page, copy, flags, frame_allocator)
+            .unwrap()
+            .flush();
+    }
+
+    true
+}
+
+/// Handle a page fault on a non-present page by mapping it, if it is in a demand paged region
+///
+/// Returns whether the fault was handled. The frames come from a reserve, which is refilled if the
+/// memory controller isn't locked.
+pub fn map_on_demand(address: VirtAddr) -> bool {
+    if !demand_pager::map_on_demand(Page::containing_address(address)) {
+        return false;
+    }
+
+    if let Some(mut controller) = MEMORY_CONTROLLER.get().and_then(Mutex::try_lock) {
+        demand_pager::refill(&mut controller.frame_allocator);
+    }
+    true
+}
+
+/// Find the kernel memory region that contains `address`
+///
+/// Stacks are only looked up if the memory controller is not locked, so this can be called from a
+/// fault handler
+pub fn region_of(address: VirtAddr) -> Option<Region> {
+    let page = Page::containing_address(address);
+
+    if P4_GUARD_PAGE.get() == Some(&page) {
+        return Some(Region::P4GuardPage);
+    }
+
+    let controller = MEMORY_CONTROLLER.get()?.try_lock()?;
+    match controller.regions.find(address)?.kind() {
+        RegionKind::Heap => Some(Region::Heap),
+        RegionKind::Stacks => controller
+            .stack_allocator
+            .stack_guarded_by(page)
+            .cloned()
+            .map(Region::StackGuardPage),
+        _ => None,
+    }
+}
+
+/// Print the heap and frame allocator usage
+///
+/// The allocators are only inspected if they are not locked, so this can be called while handling
+/// an allocation error
+pub fn print_usage() {
+    match HEAP_ALLOCATOR.try_lock() {
+        Some(mut heap) => {
+            println!(
+                "  Heap: {} of {} bytes used, {} bytes free",
+                heap.used(),
+                heap.size(),
+                heap.free()
+            );
+            println!(
+                "  Largest free block: {} bytes",
+                largest_free_block(&mut heap)
+            );
+        }
+        None => println!("  Heap: locked"),
+    }
+
+    match MEMORY_CONTROLLER.get().map(Mutex::try_lock) {
+        Some(Some(controller)) => println!(
+            "  Frames: {} of {} allocated",
+            controller.frame_allocator.allocated_frames(),
+            controller.frame_allocator.total_frames()
+        ),
+        Some(None) => println!("  Frames: locked"),
+        None => println!("  Frames: not initialized"),
+    }
+}
+
+/// Find the size of the largest block that can currently be allocated from the heap
+///
+/// The heap doesn't expose its free list, so this probes it with a binary search over allocation
+/// sizes
+fn largest_free_block(heap: &mut Heap) -> usize {
+    let mut low = 0;
+    let mut high = heap.free();
+
+    while low < high {
+        let size = low + (high - low + 1) / 2;
+        let layout = Layout::from_size_align(size, 1).unwrap();
+
+        match heap.allocate_first_fit(layout) {
+            Ok(pointer) => {
+                unsafe { heap.deallocate(pointer, layout) };
+                low = size;
+            }
+            Err(()) => high = size - 1,
+        }
+    }
+
+    low
 }
 
 /// Initialize the memory
 ///
 /// SAFTEY: This function should only be called once
-pub unsafe fn init(boot_info: &'static BootInformation) -> MemoryController {
+pub unsafe fn init(boot_info: &'static BootInformation) -> &'static Mutex<MemoryController> {
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
     let elf_sections_tag = boot_info.elf_sections().expect("ELF-sections tag required");
 
@@ -62,59 +550,139 @@ pub unsafe fn init(boot_info: &'static BootInformation) -> MemoryController {
         .max()
         .unwrap();
 
+    let kernel_start = kernel_virt_to_phys(kernel_start);
+    let kernel_end = kernel_virt_to_phys(kernel_end);
+    let multiboot_start = kernel_virt_to_phys(boot_info.start_address() as u64);
+    let multiboot_end = kernel_virt_to_phys(boot_info.end_address() as u64);
+
     println!("Kernel address: {:#x}-{:#x}", kernel_start, kernel_end);
     println!(
         "Multiboot information address: {:#x}-{:#x}",
-        boot_info.start_address(),
-        boot_info.end_address()
+        multiboot_start, multiboot_end
     );
 
     // Create the allocator
     let mut frame_allocator = AreaFrameAllocator::new(
-        PhysAddr::new(kernel_start),
-        PhysAddr::new(kernel_end),
-        PhysAddr::new(boot_info.start_address() as u64),
-        PhysAddr::new(boot_info.end_address() as u64),
+        kernel_start,
+        kernel_end,
+        multiboot_start,
+        multiboot_end,
         memory_map_tag.memory_areas(),
     );
 
     // Remap the kernel
     unsafe { remap_kernel(&mut frame_allocator, &boot_info) };
+    unsafe { address_space::init() };
+    unsafe { mmio::init() };
 
-    let mut active_page_table = get_active_page_table();
-
-    // Map the heap
-    let heap_start_page = Page::containing_address(VirtAddr::new(HEAP_START as u64));
-    let heap_end_page =
-        Page::containing_address(VirtAddr::new(HEAP_START as u64 + HEAP_SIZE as u64 - 1));
-
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_page_table
-            .map_to(
-                page,
-                frame_allocator
-                    .allocate_frame()
-                    .expect("Failed to allocate heap frame"),
-                PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-                &mut frame_allocator,
-            )
-            .unwrap()
-            .flush();
-    }
+    let active_page_table = get_active_page_table();
+
+    // Place the heap at a random address in the dynamic area, before the region allocator exists
+    // since the region allocator needs the heap
+    let heap_pages = (DYNAMIC_AREA_END - DYNAMIC_AREA_START - HEAP_SIZE as u64) / Size4KiB::SIZE;
+    let heap_start =
+        VirtAddr::new(DYNAMIC_AREA_START + kaslr::random_below(heap_pages + 1) * Size4KiB::SIZE);
+
+    // The heap is backed on demand, from frames reserved for the page fault handler
+    demand_pager::refill(&mut frame_allocator);
+    demand_pager::set_heap(
+        heap_start,
+        HEAP_SIZE as u64,
+        PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+    );
 
     // Initialize the heap allocator
-    unsafe { HEAP_ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) };
+    unsafe {
+        HEAP_ALLOCATOR
+            .lock()
+            .init(heap_start.as_mut_ptr(), HEAP_SIZE)
+    };
+
+    let mut regions = RegionAllocator::new(
+        VirtAddr::new(DYNAMIC_AREA_START),
+        VirtAddr::new(DYNAMIC_AREA_END),
+    );
+    for section in boot_info.elf_sections().unwrap() {
+        if !section.is_allocated() || section.start_address() < KERNEL_OFFSET {
+            continue;
+        }
+        // Sections are never unmapped, so their names can be leaked
+        let name = String::from(section.name().unwrap_or("unknown")).leak();
+        regions
+            .reserve(
+                name,
+                RegionKind::Kernel,
+                VirtAddr::new(section.start_address()),
+                section.size(),
+                section_flags(&section),
+            )
+            .unwrap_or_else(|| panic!("Section {} overlaps another region", name));
+    }
+    // The physical memory map covers the merged ranges mapped in `remap_kernel`
+    let physical_memory_end = physical_memory_ranges(memory_map_tag.memory_areas())
+        .map(|(_, end)| end)
+        .max()
+        .unwrap();
+    regions
+        .reserve(
+            "physical memory map",
+            RegionKind::PhysicalMemory,
+            VirtAddr::new(PHYSICAL_MEMORY_OFFSET),
+            physical_memory_end.as_u64(),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .expect("Physical memory map overlaps another region");
+    if let Some(guard_page) = P4_GUARD_PAGE.get() {
+        regions
+            .reserve(
+                "boot stack guard page",
+                RegionKind::Guard,
+                guard_page.start_address(),
+                guard_page.size(),
+                PageTableFlags::empty(),
+            )
+            .expect("Boot stack guard page overlaps another region");
+    }
+    regions
+        .reserve_demand_paged(
+            "kernel heap",
+            RegionKind::Heap,
+            heap_start,
+            HEAP_SIZE as u64,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .expect("Heap overlaps another region");
 
-    let stack_allocator = stack_allocator::StackAllocator::new(Page::range_inclusive(
-        heap_end_page + 1,
-        heap_end_page + 101,
+    let stack_area = regions
+        .allocate_demand_paged(
+            "kernel stacks",
+            RegionKind::Stacks,
+            STACK_AREA_SIZE,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .expect("Failed to allocate the stack area");
+    let stack_allocator = StackAllocator::new(Page::range_inclusive(
+        Page::containing_address(stack_area.start()),
+        Page::containing_address(stack_area.end() - 1u64),
     ));
 
-    MemoryController {
-        active_page_table,
-        frame_allocator,
-        stack_allocator,
+    // Printing the layout would defeat its randomization, so it is only printed in debug builds
+    if cfg!(debug_assertions) {
+        regions.print_layout();
     }
+    audit::audit_page_table(cfg!(debug_assertions));
+
+    // Replace the reserved frames that backed the heap so far
+    demand_pager::refill(&mut frame_allocator);
+
+    MEMORY_CONTROLLER.call_once(|| {
+        Mutex::new(MemoryController {
+            active_page_table,
+            frame_allocator,
+            stack_allocator,
+            regions,
+        })
+    })
 }
 
 /// Remap the kernel to a new page table, and activate the new page table
@@ -128,11 +696,13 @@ unsafe fn remap_kernel<A: FrameAllocator<Size4KiB>>(
     let new_table_frame = frame_allocator
         .allocate_frame()
         .expect("No frames available");
-    let new_table = &mut *(new_table_frame.start_address().as_u64() as *mut PageTable);
+    // The boot page tables map the first GiB of physical memory at `KERNEL_OFFSET`
+    let new_table =
+        &mut *((KERNEL_OFFSET + new_table_frame.start_address().as_u64()) as *mut PageTable);
     // Clear the table
     new_table.zero();
     // Set up recursive mapping
-    new_table[511].set_addr(
+    new_table[RECURSIVE_INDEX].set_addr(
         new_table_frame.start_address(),
         PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
     );
@@ -142,10 +712,10 @@ unsafe fn remap_kernel<A: FrameAllocator<Size4KiB>>(
 
     {
         // Overwrite recursive mapping
-        let mut active_page_table = get_active_page_table();
-        // The table is recursively mapped, so the last entry points to its physical address
-        original_page_table_address = active_page_table.level_4_table()[511].addr();
-        active_page_table.level_4_table()[511].set_addr(
+        let mut active_page_table = get_recursive_page_table();
+        // The table is recursively mapped, so the recursive entry points to its physical address
+        original_page_table_address = active_page_table.level_4_table()[RECURSIVE_INDEX].addr();
+        active_page_table.level_4_table()[RECURSIVE_INDEX].set_addr(
             new_table_frame.start_address(),
             PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
         );
@@ -157,66 +727,164 @@ unsafe fn remap_kernel<A: FrameAllocator<Size4KiB>>(
                 // Section is not loaded to memory
                 continue;
             }
-
-            let mut flags = PageTableFlags::empty();
-
-            if section.flags().contains(ElfSectionFlags::ALLOCATED) {
-                // section is loaded to memory
-                flags = flags | PageTableFlags::PRESENT;
-            }
-            if section.flags().contains(ElfSectionFlags::WRITABLE) {
-                flags = flags | PageTableFlags::WRITABLE;
-            }
-            if !section.flags().contains(ElfSectionFlags::EXECUTABLE) {
-                flags = flags | PageTableFlags::NO_EXECUTE;
+            if section.start_address() < KERNEL_OFFSET {
+                // The boot code is only used before jumping to the higher half
+                continue;
             }
 
-            let start_frame =
-                PhysFrame::<Size4KiB>::from_start_address(PhysAddr::new(section.start_address()))
+            let flags = section_flags(&section);
+
+            let start_page =
+                Page::<Size4KiB>::from_start_address(VirtAddr::new(section.start_address()))
                     .expect("Kernel sections not aligned");
-            let end_frame = PhysFrame::containing_address(PhysAddr::new(section.end_address() - 1));
-            // Identity map the new table
-            for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+            let end_page = Page::containing_address(VirtAddr::new(section.end_address() - 1));
+            // Map the section to its higher half address
+            for page in Page::range_inclusive(start_page, end_page) {
+                let frame = PhysFrame::containing_address(kernel_virt_to_phys(
+                    page.start_address().as_u64(),
+                ));
                 active_page_table
-                    .identity_map(frame, flags, frame_allocator)
+                    .map_to(page, frame, flags, frame_allocator)
                     .unwrap()
                     .flush();
             }
         }
 
-        // identity map the VGA text buffer
-        let vga_buffer_frame: PhysFrame<Size4KiB> =
-            PhysFrame::containing_address(PhysAddr::new(0xb8000));
-        active_page_table
-            .identity_map(
-                vga_buffer_frame,
-                PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-                frame_allocator,
-            )
-            .unwrap()
-            .flush();
+        // Map the VGA text buffer to its higher half address
+        map_kernel_frame(
+            &mut active_page_table,
+            PhysFrame::containing_address(PhysAddr::new(0xb8000)),
+            PageTableFlags::WRITABLE | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            frame_allocator,
+        );
 
+        // Map the multiboot information to its higher half address
         let multiboot_start =
-            PhysFrame::containing_address(PhysAddr::new(boot_info.start_address() as u64));
+            PhysFrame::containing_address(kernel_virt_to_phys(boot_info.start_address() as u64));
         let multiboot_end =
-            PhysFrame::containing_address(PhysAddr::new(boot_info.end_address() as u64 - 1));
-        for frame in PhysFrame::<Size4KiB>::range_inclusive(multiboot_start, multiboot_end) {
-            active_page_table
-                .identity_map(frame, PageTableFlags::PRESENT, frame_allocator)
-                .unwrap()
-                .flush();
+            PhysFrame::containing_address(kernel_virt_to_phys(boot_info.end_address() as u64 - 1));
+        for frame in PhysFrame::range_inclusive(multiboot_start, multiboot_end) {
+            map_kernel_frame(
+                &mut active_page_table,
+                frame,
+                PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+                frame_allocator,
+            );
+        }
+
+        // Map all RAM at `PHYSICAL_MEMORY_OFFSET`, using huge pages where possible
+        let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+        for (start, end) in physical_memory_ranges(memory_map_tag.memory_areas()) {
+            huge_pages::map_contiguous(
+                &mut active_page_table,
+                phys_to_virt(start),
+                start,
+                end - start,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+                frame_allocator,
+            )
+            .expect("Failed to map physical memory");
         }
     }
 
     Cr3::write(new_table_frame, Cr3::read().1);
 
     // Turn the original p4 page into a guard page
-    let old_p4_page =
-        Page::<Size4KiB>::containing_address(VirtAddr::new(original_page_table_address.as_u64()));
+    let old_p4_page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+        KERNEL_OFFSET + original_page_table_address.as_u64(),
+    ));
     get_active_page_table()
         .unmap(old_p4_page)
         .unwrap()
         .1
         .flush();
+    P4_GUARD_PAGE.call_once(|| old_p4_page);
     println!("Guard page at {:#x}", old_p4_page.start_address());
 }
+
+/// Whether a memory area is RAM that the kernel can use
+fn is_available(area: &MemoryArea) -> bool {
+    MemoryAreaType::from(area.typ()) == MemoryAreaType::Available
+}
+
+/// The whole frames of the available memory areas, merged where they overlap or touch
+///
+/// Only RAM is mapped, since the frames of other areas are mapped uncached or write-combining by
+/// `ioremap`, and mapping a frame with two memory types is undefined. The memory map isn't sorted
+/// and its areas can overlap, so mapping them one by one could map a frame twice. The ranges are
+/// found without allocating, since this runs before the heap exists.
+fn physical_memory_ranges<'a>(
+    areas: &'a [MemoryArea],
+) -> impl Iterator<Item = (PhysAddr, PhysAddr)> + 'a {
+    let aligned_areas = move || {
+        areas
+            .iter()
+            .filter(|area| is_available(area))
+            .map(|area| {
+                (
+                    PhysAddr::new(area.start_address()).align_up(Size4KiB::SIZE),
+                    PhysAddr::new(area.end_address()).align_down(Size4KiB::SIZE),
+                )
+            })
+            .filter(|&(start, end)| start < end)
+    };
+    let mut previous_end = PhysAddr::new(0);
+
+    iter::from_fn(move || {
+        // The lowest area that isn't covered yet starts the next range
+        let start = aligned_areas()
+            .filter(|&(_, end)| end > previous_end)
+            .map(|(start, _)| start.max(previous_end))
+            .min()?;
+
+        // Extend the range by all areas starting inside or right after it
+        let mut end = start;
+        loop {
+            let extended_end = aligned_areas()
+                .filter(|&(area_start, _)| area_start <= end)
+                .map(|(_, area_end)| area_end)
+                .fold(end, PhysAddr::max);
+            if extended_end == end {
+                break;
+            }
+            end = extended_end;
+        }
+
+        previous_end = end;
+        Some((start, end))
+    })
+}
+
+/// Get the flags an ELF section should be mapped with
+fn section_flags(section: &ElfSection) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+
+    if section.flags().contains(ElfSectionFlags::ALLOCATED) {
+        // section is loaded to memory
+        flags = flags | PageTableFlags::PRESENT;
+    }
+    if section.flags().contains(ElfSectionFlags::WRITABLE) {
+        flags = flags | PageTableFlags::WRITABLE;
+    }
+    if !section.flags().contains(ElfSectionFlags::EXECUTABLE) {
+        flags = flags | PageTableFlags::NO_EXECUTE;
+    }
+
+    flags
+}
+
+/// Map a frame to its address in the higher half, at `KERNEL_OFFSET` above its physical address
+unsafe fn map_kernel_frame<M: Mapper<Size4KiB>, A: FrameAllocator<Size4KiB>>(
+    page_table: &mut M,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) {
+    let page = Page::containing_address(VirtAddr::new(
+        KERNEL_OFFSET + frame.start_address().as_u64(),
+    ));
+    page_table
+        .map_to(page, frame, flags, frame_allocator)
+        .unwrap()
+        .flush();
+}
@@ -0,0 +1,306 @@
+use alloc::vec::Vec;
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::kaslr;
+
+/// How often a random start address is tried before falling back to the first free range
+const RANDOM_ATTEMPTS: usize = 16;
+
+/// What a virtual memory region is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// The kernel image
+    Kernel,
+    /// A mapping of physical memory
+    PhysicalMemory,
+    /// A mapping of memory mapped I/O
+    Mmio,
+    /// The kernel heap
+    Heap,
+    /// The range kernel stacks are allocated from
+    Stacks,
+    /// An unmapped page that catches overflows
+    Guard,
+    /// Memory that isn't used for anything in particular, like large buffers
+    Anonymous,
+}
+
+/// A named range of virtual memory
+#[derive(Debug, Clone)]
+pub struct VirtualRegion {
+    name: &'static str,
+    kind: RegionKind,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    demand_paged: bool,
+}
+
+impl VirtualRegion {
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// The first address after the region
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The flags the pages of the region are mapped with
+    pub fn flags(&self) -> PageTableFlags {
+        self.flags
+    }
+
+    /// Whether the pages of the region are only backed by frames when they are first accessed
+    #[allow(dead_code)]
+    pub fn is_demand_paged(&self) -> bool {
+        self.demand_paged
+    }
+
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        address >= self.start && address < self.end()
+    }
+}
+
+/// Tracks the regions of the kernel address space, and allocates free ranges for new regions
+pub struct RegionAllocator {
+    /// The range that `allocate` hands out regions from
+    area_start: VirtAddr,
+    area_end: VirtAddr,
+    /// All regions, sorted by their start address
+    regions: Vec<VirtualRegion>,
+}
+
+impl RegionAllocator {
+    pub fn new(area_start: VirtAddr, area_end: VirtAddr) -> Self {
+        Self {
+            area_start,
+            area_end,
+            regions: Vec::new(),
+        }
+    }
+
+    /// Track a region at a fixed address
+    ///
+    /// Returns `None` if it overlaps an existing region
+    pub fn reserve(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Option<&VirtualRegion> {
+        self.reserve_region(name, kind, start, size, flags, false)
+    }
+
+    /// Like `reserve`, but the pages of the region are mapped by the page fault handler when they
+    /// are first accessed
+    pub fn reserve_demand_paged(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Option<&VirtualRegion> {
+        self.reserve_region(name, kind, start, size, flags, true)
+    }
+
+    fn reserve_region(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        demand_paged: bool,
+    ) -> Option<&VirtualRegion> {
+        if self.overlaps(start, start + size) {
+            return None;
+        }
+
+        Some(self.insert(VirtualRegion {
+            name,
+            kind,
+            start,
+            size,
+            flags,
+            demand_paged,
+        }))
+    }
+
+    /// Find a free range of `size` bytes (rounded up to whole pages) at a random address, and track
+    /// it as a region
+    #[allow(dead_code)]
+    pub fn allocate(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Option<&VirtualRegion> {
+        self.allocate_region(name, kind, size, Size4KiB::SIZE, flags, false)
+    }
+
+    /// Like `allocate`, but the region starts at a multiple of `align`, which must be a multiple
+    /// of the page size
+    pub fn allocate_aligned(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        size: u64,
+        align: u64,
+        flags: PageTableFlags,
+    ) -> Option<&VirtualRegion> {
+        self.allocate_region(name, kind, size, align, flags, false)
+    }
+
+    /// Like `allocate`, but the pages of the region are mapped by the page fault handler when they
+    /// are first accessed
+    pub fn allocate_demand_paged(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Option<&VirtualRegion> {
+        self.allocate_region(name, kind, size, Size4KiB::SIZE, flags, true)
+    }
+
+    fn allocate_region(
+        &mut self,
+        name: &'static str,
+        kind: RegionKind,
+        size: u64,
+        align: u64,
+        flags: PageTableFlags,
+        demand_paged: bool,
+    ) -> Option<&VirtualRegion> {
+        let size = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE * Size4KiB::SIZE;
+
+        let start = match self.random_start(size, align) {
+            Some(start) => start,
+            None => self.first_fit(size, align)?,
+        };
+
+        Some(self.insert(VirtualRegion {
+            name,
+            kind,
+            start,
+            size,
+            flags,
+            demand_paged,
+        }))
+    }
+
+    /// Stop tracking the region starting at `start`, so its range can be reused
+    pub fn free(&mut self, start: VirtAddr) -> Option<VirtualRegion> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.start == start)?;
+
+        Some(self.regions.remove(index))
+    }
+
+    /// Find the region containing `address`
+    pub fn find(&self, address: VirtAddr) -> Option<&VirtualRegion> {
+        self.regions.iter().find(|region| region.contains(address))
+    }
+
+    /// Print all regions, for debugging
+    pub fn print_layout(&self) {
+        println!("Virtual memory layout:");
+        for region in self.regions.iter() {
+            println!(
+                "  {:#018x}-{:#018x} {}{}{}{} {:?} ({})",
+                region.start,
+                region.end(),
+                if region.flags.contains(PageTableFlags::PRESENT) {
+                    "r"
+                } else {
+                    "-"
+                },
+                if region.flags.contains(PageTableFlags::WRITABLE) {
+                    "w"
+                } else {
+                    "-"
+                },
+                if region.flags.contains(PageTableFlags::NO_EXECUTE) {
+                    "-"
+                } else {
+                    "x"
+                },
+                if region.demand_paged { " lazy" } else { "" },
+                region.kind,
+                region.name
+            );
+        }
+    }
+
+    /// Try a few random aligned start addresses for a free range
+    fn random_start(&self, size: u64, align: u64) -> Option<VirtAddr> {
+        let first = self.area_start.align_up(align);
+        if first + size > self.area_end {
+            return None;
+        }
+        let slots = (self.area_end - size - first) / align + 1;
+
+        (0..RANDOM_ATTEMPTS)
+            .map(|_| first + kaslr::random_below(slots) * align)
+            .find(|&start| !self.overlaps(start, start + size))
+    }
+
+    /// Find the lowest aligned start address of a free range
+    fn first_fit(&self, size: u64, align: u64) -> Option<VirtAddr> {
+        let mut start = self.area_start.align_up(align);
+        for region in self.regions.iter() {
+            if region.end() <= start {
+                continue;
+            }
+            if region.start >= start + size {
+                break;
+            }
+            start = region.end().align_up(align);
+        }
+        if start + size > self.area_end {
+            return None;
+        }
+
+        Some(start)
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.regions
+            .iter()
+            .any(|region| start < region.end() && region.start < end)
+    }
+
+    fn insert(&mut self, region: VirtualRegion) -> &VirtualRegion {
+        let index = self
+            .regions
+            .iter()
+            .position(|existing| existing.start > region.start)
+            .unwrap_or(self.regions.len());
+        self.regions.insert(index, region);
+
+        &self.regions[index]
+    }
+}
@@ -1,22 +1,29 @@
+use alloc::vec::Vec;
 use x86_64::{
     structures::paging::{
-        page::PageRangeInclusive, FrameAllocator, Mapper, Page, PageTableFlags, RecursivePageTable,
-        Size4KiB,
+        mapper::UnmapError, page::PageRangeInclusive, FrameAllocator, FrameDeallocator, Mapper,
+        Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Stack {
+    name: &'static str,
     top: VirtAddr,
     bottom: VirtAddr,
 }
 
 #[allow(dead_code)]
 impl Stack {
-    fn new(top: VirtAddr, bottom: VirtAddr) -> Stack {
+    fn new(name: &'static str, top: VirtAddr, bottom: VirtAddr) -> Stack {
         assert!(top > bottom);
-        Stack { top, bottom }
+        Stack { name, top, bottom }
+    }
+
+    /// The name of the stack, used when reporting stack overflows
+    pub fn name(&self) -> &'static str {
+        self.name
     }
 
     pub fn top(&self) -> VirtAddr {
@@ -26,67 +33,168 @@ impl Stack {
     pub fn bottom(&self) -> VirtAddr {
         self.bottom
     }
+
+    /// The unmapped page below the stack
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom) - 1
+    }
+
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        address >= self.bottom && address < self.top
+    }
 }
 
 pub struct StackAllocator {
+    /// The pages that were never used by a stack
     range: PageRangeInclusive,
+    /// Pages of deallocated stacks (including their guard pages) that can be reused
+    free_ranges: Vec<PageRangeInclusive>,
+    stacks: Vec<Stack>,
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageRangeInclusive) -> Self {
-        Self { range: page_range }
+        Self {
+            range: page_range,
+            free_ranges: Vec::new(),
+            stacks: Vec::new(),
+        }
+    }
+
+    /// Find the allocated stack whose guard page is `page`
+    pub fn stack_guarded_by(&self, page: Page) -> Option<&Stack> {
+        self.stacks.iter().find(|stack| stack.guard_page() == page)
     }
 
-    pub fn allocate_stack<A: FrameAllocator<Size4KiB>>(
+    pub fn allocate_stack<M: Mapper<Size4KiB>, A: FrameAllocator<Size4KiB>>(
         &mut self,
-        active_table: &mut RecursivePageTable,
+        active_table: &mut M,
         frame_allocator: &mut A,
+        name: &'static str,
         size_in_pages: usize,
     ) -> Option<Stack> {
+        let stack = self.reserve_stack(name, size_in_pages)?;
+
+        // Map the stack to physical frames
+        let start = Page::containing_address(stack.bottom);
+        let end = Page::containing_address(stack.top - 1u64);
+        for page in Page::range_inclusive(start, end) {
+            unsafe {
+                active_table
+                    .map_to(
+                        page,
+                        frame_allocator
+                            .allocate_frame()
+                            .expect("Failed to allocate frame"),
+                        PageTableFlags::WRITABLE
+                            | PageTableFlags::PRESENT
+                            | PageTableFlags::NO_EXECUTE,
+                        frame_allocator,
+                    )
+                    .unwrap()
+                    .flush();
+            }
+        }
+
+        Some(stack)
+    }
+
+    /// Allocate a stack without mapping it, its pages are mapped by the page fault handler when
+    /// they are first accessed
+    ///
+    /// Stacks used by exception handlers must be allocated with `allocate_stack`, since a page
+    /// fault on them can't be handled
+    pub fn reserve_stack(&mut self, name: &'static str, size_in_pages: usize) -> Option<Stack> {
         if size_in_pages == 0 {
             return None;
         }
 
-        // Clone the range, since we only want to change it on success
-        let mut range = self.range.clone();
+        // Take the stack pages and a guard page below them
+        let pages = self.take_pages(size_in_pages as u64 + 1)?;
+        let start = pages.start + 1;
+        let end = pages.end;
 
-        // Try to allocate the stack pages and a guard page
-        let guard_page = range.next();
-        let stack_start = range.next();
-        let stack_end = if size_in_pages == 1 {
-            stack_start
-        } else {
-            range.nth(size_in_pages - 2)
-        };
+        let stack = Stack::new(
+            name,
+            end.start_address() + end.size(),
+            start.start_address(),
+        );
+        self.stacks.push(stack.clone());
 
-        match (guard_page, stack_start, stack_end) {
-            (Some(_), Some(start), Some(end)) => {
-                // Success, update the page range
-                self.range = range;
-
-                // Map the stack to physical frames
-                for page in Page::range_inclusive(start, end) {
-                    unsafe {
-                        active_table
-                            .map_to(
-                                page,
-                                frame_allocator
-                                    .allocate_frame()
-                                    .expect("Failed to allocate frame"),
-                                PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
-                                frame_allocator,
-                            )
-                            .unwrap()
-                            .flush();
-                    }
+        Some(stack)
+    }
+
+    /// Unmap a stack, returning its frames to `frame_allocator` and its pages to the allocator
+    ///
+    /// SAFTEY: The stack must not be in use
+    pub unsafe fn deallocate_stack<M: Mapper<Size4KiB>, A: FrameDeallocator<Size4KiB>>(
+        &mut self,
+        active_table: &mut M,
+        frame_allocator: &mut A,
+        stack: Stack,
+    ) {
+        let index = self
+            .stacks
+            .iter()
+            .position(|allocated| allocated.bottom == stack.bottom)
+            .expect("Stack was not allocated by this allocator");
+        self.stacks.swap_remove(index);
+
+        let start = Page::containing_address(stack.bottom);
+        let end = Page::containing_address(stack.top - 1u64);
+        for page in Page::range_inclusive(start, end) {
+            match active_table.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    frame_allocator.deallocate_frame(frame);
                 }
+                // Pages of reserved stacks that were never accessed aren't mapped
+                Err(UnmapError::PageNotMapped) => {}
+                Err(error) => panic!("Failed to unmap stack page: {:?}", error),
+            }
+        }
+
+        self.free_pages(Page::range_inclusive(stack.guard_page(), end));
+    }
 
-                Some(Stack::new(
-                    end.start_address() + end.size(),
-                    start.start_address(),
-                ))
+    /// Take `amount` consecutive pages, preferring pages of deallocated stacks
+    fn take_pages(&mut self, amount: u64) -> Option<PageRangeInclusive> {
+        let range = match self
+            .free_ranges
+            .iter()
+            .position(|range| range.end - range.start + 1 >= amount)
+        {
+            Some(index) => &mut self.free_ranges[index],
+            None if !self.range.is_empty() && self.range.end - self.range.start + 1 >= amount => {
+                &mut self.range
             }
-            _ => None,
+            None => return None,
+        };
+
+        let pages = Page::range_inclusive(range.start, range.start + (amount - 1));
+        range.start = pages.end + 1;
+
+        // Drop free ranges that were used up
+        self.free_ranges.retain(|range| !range.is_empty());
+
+        Some(pages)
+    }
+
+    /// Return pages to the allocator, merging them with adjacent free pages
+    fn free_pages(&mut self, mut pages: PageRangeInclusive) {
+        while let Some(index) = self
+            .free_ranges
+            .iter()
+            .position(|range| range.end + 1 == pages.start || pages.end + 1 == range.start)
+        {
+            let range = self.free_ranges.swap_remove(index);
+            pages = Page::range_inclusive(range.start.min(pages.start), range.end.max(pages.end));
+        }
+
+        if pages.end + 1 == self.range.start {
+            self.range.start = pages.start;
+        } else {
+            self.free_ranges.push(pages);
         }
     }
 }
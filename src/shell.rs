@@ -0,0 +1,183 @@
+use core::time::Duration;
+
+use alloc::string::String;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::acpi;
+use crate::interrupts::irq_statistics;
+use crate::time::{self, Instant, TimerId};
+
+/// The amount of typed characters that can be queued before the shell reads them
+const INPUT_CAPACITY: usize = 64;
+
+/// The periodic timer started by the `ticker` command
+static TICKER: Mutex<Option<TimerId>> = Mutex::new(None);
+
+/// The characters typed on the keyboard, waiting to be read by the shell
+static INPUT: Mutex<InputQueue> = Mutex::new(InputQueue::new());
+
+/// A ring buffer of characters, which can be filled from interrupt handlers without allocating
+struct InputQueue {
+    characters: [char; INPUT_CAPACITY],
+    start: usize,
+    length: usize,
+}
+
+impl InputQueue {
+    const fn new() -> InputQueue {
+        InputQueue {
+            characters: ['\0'; INPUT_CAPACITY],
+            start: 0,
+            length: 0,
+        }
+    }
+
+    /// Add a character, dropping it if the queue is full
+    fn push(&mut self, character: char) {
+        if self.length < INPUT_CAPACITY {
+            self.characters[(self.start + self.length) % INPUT_CAPACITY] = character;
+            self.length += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        if self.length == 0 {
+            return None;
+        }
+
+        let character = self.characters[self.start];
+        self.start = (self.start + 1) % INPUT_CAPACITY;
+        self.length -= 1;
+        Some(character)
+    }
+}
+
+/// Queue a typed character for the shell
+///
+/// This is called from the keyboard interrupt handler
+pub fn push_character(character: char) {
+    INPUT.lock().push(character);
+}
+
+/// Wait for the next typed character
+fn read_character() -> char {
+    loop {
+        // Interrupts are disabled while checking the queue, so a key press can't arrive between
+        // finding it empty and halting
+        interrupts::disable();
+        if let Some(character) = INPUT.lock().pop() {
+            interrupts::enable();
+            return character;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Read and run commands forever
+pub fn run() -> ! {
+    let mut line = String::new();
+
+    loop {
+        print!("> ");
+        line.clear();
+
+        loop {
+            match read_character() {
+                '\n' => {
+                    println!();
+                    break;
+                }
+                '\u{8}' => {
+                    if line.pop().is_some() {
+                        print!("\u{8}");
+                    }
+                }
+                character if !character.is_control() => {
+                    line.push(character);
+                    print!("{}", character);
+                }
+                _ => {}
+            }
+        }
+
+        let mut words = line.split_whitespace();
+        if let Some(command) = words.next() {
+            run_command(command, words);
+        }
+    }
+}
+
+fn run_command<'a, I: Iterator<Item = &'a str>>(command: &str, mut arguments: I) {
+    match command {
+        "help" => {
+            println!("Commands:");
+            println!("  help      show this list");
+            println!("  date      show the date and time");
+            println!("  uptime    show the time since boot");
+            println!("  irqs      show the interrupt counters");
+            println!("  sleep MS  wait for MS milliseconds");
+            println!("  alarm MS  print a message after MS milliseconds");
+            println!("  ticker MS print a dot every MS milliseconds, or stop");
+            println!("  shutdown  turn the computer off");
+            println!("  reboot    restart the computer");
+        }
+        "date" => println!("{} ({})", time::date_time(), time::now()),
+        "uptime" => {
+            let uptime = time::uptime();
+            println!("{}.{:03} s", uptime.as_secs(), uptime.subsec_millis());
+        }
+        "irqs" => {
+            for line in irq_statistics() {
+                if line.count != 0 || line.spurious != 0 || !line.handlers.is_empty() {
+                    println!(
+                        "  IRQ {:>2}: {:>8} ({} unhandled, {} spurious) {}",
+                        line.irq,
+                        line.count,
+                        line.unhandled,
+                        line.spurious,
+                        line.handlers.join(", ")
+                    );
+                }
+            }
+        }
+        "sleep" => match arguments.next().map(str::parse) {
+            Some(Ok(milliseconds)) => time::sleep(Duration::from_millis(milliseconds)),
+            _ => println!("Usage: sleep MILLISECONDS"),
+        },
+        "alarm" => match arguments.next().map(str::parse) {
+            Some(Ok(milliseconds)) => {
+                time::add_timer(Instant::now() + Duration::from_millis(milliseconds), || {
+                    println!("Alarm");
+                });
+            }
+            _ => println!("Usage: alarm MILLISECONDS"),
+        },
+        "ticker" => {
+            let mut ticker = TICKER.lock();
+            if let Some(id) = ticker.take() {
+                time::cancel_timer(id);
+                println!();
+                return;
+            }
+            match arguments.next().map(str::parse) {
+                Some(Ok(milliseconds)) => {
+                    *ticker = Some(time::add_periodic_timer(
+                        Duration::from_millis(milliseconds),
+                        || print!("."),
+                    ));
+                }
+                _ => println!("Usage: ticker MILLISECONDS"),
+            }
+        }
+        "shutdown" | "poweroff" => {
+            println!("Shutting down");
+            acpi::shutdown();
+        }
+        "reboot" => {
+            println!("Rebooting");
+            acpi::reboot();
+        }
+        _ => println!("Unknown command: {}", command),
+    }
+}
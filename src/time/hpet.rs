@@ -0,0 +1,209 @@
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::acpi::{self, GenericAddress, Polarity, TriggerMode};
+use crate::interrupts::apic;
+use crate::memory::{CacheType, IoMemory, MemoryController};
+
+/// The size of the register block of a HPET
+const REGISTERS_SIZE: u64 = 0x400;
+
+// HPET registers
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+const TIMER_CONFIGURATION: u64 = 0x100;
+const TIMER_COMPARATOR: u64 = 0x108;
+/// The distance between the registers of consecutive timers
+const TIMER_STRIDE: u64 = 0x20;
+
+/// `ENABLE_CNF` in the configuration register: the main counter runs
+const COUNTER_ENABLE: u64 = 1 << 0;
+
+// Timer configuration bits
+/// `Tn_INT_TYPE_CNF`: the interrupt is level triggered instead of edge triggered
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+/// `Tn_FSB_EN_CNF`: the timer uses message signaled interrupts instead of the I/O APIC
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+/// The timer used for one-shot interrupts
+const EVENT_TIMER: u64 = 0;
+
+/// The smallest amount of counter ticks a one-shot interrupt is programmed ahead
+const MINIMUM_TIMEOUT: u64 = 16;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+static HPET: Once<Mutex<Hpet>> = Once::new();
+
+/// A high precision event timer block
+struct Hpet {
+    registers: IoMemory,
+    /// The length of a tick of the main counter, in femtoseconds
+    period: u64,
+    /// The bits of the main counter, which may only be 32 bits wide
+    counter_mask: u64,
+}
+
+/// Map the HPET described by ACPI and start its main counter
+///
+/// Returns whether the system has a usable HPET
+pub fn init(memory_controller: &mut MemoryController) -> bool {
+    let table = match acpi::hpet() {
+        Some(table) => table,
+        None => return false,
+    };
+    let address = match table.base_address {
+        GenericAddress::Memory(address) => address,
+        _ => return false,
+    };
+    let registers =
+        match memory_controller.ioremap("HPET", address, REGISTERS_SIZE, CacheType::Uncached) {
+            Some(registers) => registers,
+            None => return false,
+        };
+
+    // The upper half of the capabilities is the counter period
+    let period = registers.read::<u64>(CAPABILITIES) >> 32;
+    if period == 0 {
+        return false;
+    }
+
+    // Stop all timers the firmware may have left running
+    for timer in 0..u64::from(table.comparators) {
+        let register = TIMER_CONFIGURATION + timer * TIMER_STRIDE;
+        let configuration = registers.read::<u64>(register);
+        registers.write(
+            register,
+            configuration & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+        );
+    }
+
+    let configuration = registers.read::<u64>(CONFIGURATION);
+    registers.write(CONFIGURATION, configuration | COUNTER_ENABLE);
+
+    HPET.call_once(|| {
+        Mutex::new(Hpet {
+            registers,
+            period,
+            counter_mask: if table.has_64bit_counter {
+                u64::max_value()
+            } else {
+                u64::from(u32::max_value())
+            },
+        })
+    });
+    true
+}
+
+/// Whether the main counter is 64 bits wide, so it doesn't overflow and can be used as a clock
+pub fn has_64bit_counter() -> bool {
+    with_hpet(|hpet| hpet.counter_mask == u64::max_value()).unwrap_or(false)
+}
+
+/// The frequency of the main counter, in Hz
+pub fn frequency() -> Option<u64> {
+    with_hpet(|hpet| FEMTOSECONDS_PER_SECOND / hpet.period)
+}
+
+/// The length of a tick of the main counter, in femtoseconds
+pub fn period() -> Option<u64> {
+    with_hpet(|hpet| hpet.period)
+}
+
+/// Read the main counter
+pub fn counter() -> Option<u64> {
+    with_hpet(|hpet| hpet.counter())
+}
+
+/// Busy wait until the main counter advanced by `ticks`
+///
+/// Returns `false` if the system has no HPET
+pub fn wait(ticks: u64) -> bool {
+    let start = match counter() {
+        Some(start) => start,
+        None => return false,
+    };
+    let mask = with_hpet(|hpet| hpet.counter_mask).unwrap();
+
+    while counter().unwrap().wrapping_sub(start) & mask < ticks {}
+    true
+}
+
+/// Route the event timer to `vector` through the I/O APIC, for `set_timeout`
+///
+/// Returns `false` if the system has no HPET or the timer can't be routed
+pub fn init_event_timer(vector: u8) -> bool {
+    let result = with_hpet(|hpet| {
+        let register = TIMER_CONFIGURATION + EVENT_TIMER * TIMER_STRIDE;
+        let configuration = hpet.registers.read::<u64>(register);
+
+        // The upper half of the configuration is a bitmap of the allowed I/O APIC inputs, of which
+        // the highest ones are least likely to be shared with ISA interrupts
+        let routes = (configuration >> 32) as u32;
+        if routes == 0 {
+            return false;
+        }
+        let gsi = 31 - routes.leading_zeros();
+        if !apic::route_gsi(gsi, vector, Polarity::ActiveHigh, TriggerMode::Edge) {
+            return false;
+        }
+
+        // One-shot, edge triggered interrupts on `gsi`
+        let configuration = (configuration
+            & !(TIMER_LEVEL_TRIGGERED | TIMER_PERIODIC | TIMER_FSB_ENABLE | TIMER_ROUTE_MASK))
+            | (u64::from(gsi) << TIMER_ROUTE_SHIFT);
+        hpet.registers.write(register, configuration);
+        true
+    });
+
+    result.unwrap_or(false)
+}
+
+/// Interrupt once the main counter advanced by `ticks`, or disarm the event timer if `ticks` is 0
+///
+/// `init_event_timer` has to be called first
+pub fn set_timeout(ticks: u64) {
+    with_hpet(|hpet| {
+        let register = TIMER_CONFIGURATION + EVENT_TIMER * TIMER_STRIDE;
+        let configuration = hpet.registers.read::<u64>(register);
+        if ticks == 0 {
+            hpet.registers
+                .write(register, configuration & !TIMER_INTERRUPT_ENABLE);
+            return;
+        }
+        hpet.registers
+            .write(register, configuration | TIMER_INTERRUPT_ENABLE);
+
+        // The comparator only matches when the counter passes it, so a target that passed while it
+        // was written would be missed until the counter wraps around
+        let mut timeout = ticks.max(MINIMUM_TIMEOUT);
+        loop {
+            let target = hpet.counter().wrapping_add(timeout) & hpet.counter_mask;
+            hpet.registers
+                .write(TIMER_COMPARATOR + EVENT_TIMER * TIMER_STRIDE, target);
+
+            let remaining = target.wrapping_sub(hpet.counter()) & hpet.counter_mask;
+            if remaining != 0 && remaining <= timeout {
+                break;
+            }
+            timeout *= 2;
+        }
+    });
+}
+
+/// Run `f` on the HPET with interrupts disabled, since it's also used by the timer interrupt
+fn with_hpet<T, F: FnOnce(&mut Hpet) -> T>(f: F) -> Option<T> {
+    let hpet = HPET.get()?;
+    Some(interrupts::without_interrupts(|| f(&mut hpet.lock())))
+}
+
+impl Hpet {
+    fn counter(&self) -> u64 {
+        self.registers.read::<u64>(MAIN_COUNTER)
+    }
+}
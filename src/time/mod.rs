@@ -0,0 +1,240 @@
+mod hpet;
+mod pit;
+mod rtc;
+mod tickless;
+mod tsc;
+mod wheel;
+
+use core::{
+    convert::TryFrom,
+    ops::Add,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::{register_irq, unregister_irq};
+use crate::memory::MemoryController;
+
+pub use self::rtc::{date_time, now};
+pub use self::wheel::{add_periodic_timer, add_timer, cancel_timer, TimerId};
+
+/// The frequency of the timer interrupt, in Hz
+pub const TICK_FREQUENCY: u32 = 1000;
+
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+
+/// The amount of timer interrupts since boot, from whichever timer drives `tick`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// The PIT divisor, which determines the exact duration of a tick
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+
+/// The counter measuring `Instant`s, timer ticks are counted if there is none
+static CLOCK: Once<Clock> = Once::new();
+
+/// A free running counter used as the clock
+#[derive(Debug, Clone, Copy)]
+enum Clock {
+    /// The invariant TSC, with its frequency in Hz and its value when the clock was started
+    Tsc { frequency: u64, start: u64 },
+    /// The 64-bit HPET main counter, with its period in femtoseconds and its value when the clock
+    /// was started
+    Hpet { period: u64, start: u64 },
+}
+
+/// A point in time, measured by the invariant TSC or the HPET, or by counting timer ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    /// Nanoseconds since the clock was started
+    nanoseconds: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        let nanoseconds = match CLOCK.get() {
+            Some(&Clock::Tsc { frequency, start }) => {
+                let cycles = tsc::read() - start;
+                u128::from(cycles) * NANOSECONDS_PER_SECOND / u128::from(frequency)
+            }
+            Some(&Clock::Hpet { period, start }) => {
+                let ticks = hpet::counter().unwrap() - start;
+                u128::from(ticks) * u128::from(period) / FEMTOSECONDS_PER_NANOSECOND
+            }
+            None => ticks_to_duration(ticks()).as_nanos(),
+        };
+
+        Instant {
+            nanoseconds: nanoseconds as u64,
+        }
+    }
+
+    /// The time between `earlier` and this instant, or 0 if `earlier` is later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanoseconds.saturating_sub(earlier.nanoseconds))
+    }
+
+    /// The time since this instant
+    pub fn elapsed(self) -> Duration {
+        Instant::now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Adding saturates at the latest representable instant, which is never reached
+    fn add(self, duration: Duration) -> Instant {
+        let nanoseconds = u64::try_from(duration.as_nanos()).unwrap_or(u64::max_value());
+        Instant {
+            nanoseconds: self.nanoseconds.saturating_add(nanoseconds),
+        }
+    }
+}
+
+/// Program the PIT to interrupt at `TICK_FREQUENCY`, and start the most precise clock available
+///
+/// When there is a clock, timer interrupts are only scheduled for the deadlines passed to
+/// `add_deadline`, if possible. This has to be called after the interrupt controllers are set up.
+pub fn init(memory_controller: &mut MemoryController) {
+    DIVISOR.store(pit::set_frequency(TICK_FREQUENCY), Ordering::Relaxed);
+    let pit_handler = register_irq(pit::IRQ, "PIT", || {
+        tick();
+        true
+    });
+
+    let has_hpet = hpet::init(memory_controller);
+
+    // Interrupts would make the calibration less precise
+    let clock = interrupts::without_interrupts(|| {
+        // A TSC that isn't invariant changes its rate with the CPU frequency
+        if tsc::is_invariant() {
+            let frequency = tsc::calibrate();
+            Some(Clock::Tsc {
+                frequency,
+                start: tsc::read(),
+            })
+        } else if hpet::has_64bit_counter() {
+            Some(Clock::Hpet {
+                period: hpet::period().unwrap(),
+                start: hpet::counter().unwrap(),
+            })
+        } else {
+            None
+        }
+    });
+
+    match clock {
+        Some(Clock::Tsc { frequency, .. }) => println!(
+            "Time: invariant TSC clock at {} MHz, calibrated against the {}",
+            frequency / 1_000_000,
+            if has_hpet { "HPET" } else { "PIT" }
+        ),
+        Some(Clock::Hpet { .. }) => println!(
+            "Time: HPET clock at {} MHz",
+            hpet::frequency().unwrap() / 1_000_000
+        ),
+        None => println!("Time: counting timer ticks at {} Hz", TICK_FREQUENCY),
+    }
+
+    if let Some(clock) = clock {
+        CLOCK.call_once(|| clock);
+
+        match tickless::init(clock) {
+            Some(timer) => {
+                unregister_irq(pit_handler);
+                println!("Time: tickless, using the {} timer", timer);
+            }
+            None => println!("Time: ticking at {} Hz", TICK_FREQUENCY),
+        }
+    }
+
+    // The RTC is read after the clock was started, since the time between its updates is measured
+    // with the clock
+    println!("Time: the date is {}", rtc::init());
+}
+
+/// Count a timer interrupt
+///
+/// This is called from the timer interrupt handlers
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    tickless::expire();
+    wheel::run();
+}
+
+/// Make sure a timer interrupt happens at `deadline`
+///
+/// This is only needed when the timer is tickless, otherwise the next tick comes soon anyway
+pub fn add_deadline(deadline: Instant) {
+    tickless::add_deadline(deadline);
+}
+
+/// The amount of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The time since the clock was started
+pub fn uptime() -> Duration {
+    Duration::from_nanos(Instant::now().nanoseconds)
+}
+
+/// The smallest step of the clock
+pub fn resolution() -> Duration {
+    match CLOCK.get() {
+        Some(&Clock::Tsc { .. }) => Duration::from_nanos(1),
+        Some(&Clock::Hpet { period, .. }) => {
+            Duration::from_nanos((u128::from(period) / FEMTOSECONDS_PER_NANOSECOND) as u64 + 1)
+        }
+        None => ticks_to_duration(1),
+    }
+}
+
+/// Halt until at least `duration` has passed
+///
+/// This enables interrupts, since the timer interrupt is what ends the sleep
+pub fn sleep(duration: Duration) {
+    if duration == Duration::from_secs(0) {
+        return;
+    }
+
+    // Part of the current step of the clock may already have passed. Adding saturates, so a
+    // duration too long to represent sleeps forever instead of overflowing.
+    let deadline = Instant::now() + duration + resolution();
+    add_deadline(deadline);
+
+    loop {
+        // Interrupts are disabled while checking the time, so the timer interrupt can't arrive
+        // between the check and halting
+        interrupts::disable();
+        if Instant::now() >= deadline {
+            interrupts::enable();
+            return;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Busy wait for `milliseconds` using the HPET if it's available and else the PIT, without
+/// interrupts
+///
+/// The PIT can wait for at most 54 milliseconds
+fn busy_wait(milliseconds: u64) {
+    match hpet::frequency() {
+        Some(frequency) => {
+            hpet::wait(frequency * milliseconds / 1000);
+        }
+        None => pit::wait((u64::from(pit::BASE_FREQUENCY) * milliseconds / 1000) as u16),
+    }
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let divisor = u128::from(DIVISOR.load(Ordering::Relaxed));
+    let nanoseconds =
+        u128::from(ticks) * divisor * NANOSECONDS_PER_SECOND / u128::from(pit::BASE_FREQUENCY);
+
+    Duration::from_nanos(nanoseconds as u64)
+}
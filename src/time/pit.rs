@@ -0,0 +1,69 @@
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// The frequency of the oscillator driving the PIT, in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+/// The ISA interrupt of channel 0
+pub const IRQ: u8 = 0;
+
+/// The data port of channel 0, which is connected to IRQ 0
+const CHANNEL_0_PORT: u16 = 0x40;
+/// The data port of channel 2, whose gate and output are in the PC speaker control port
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+const SPEAKER_CONTROL_PORT: u16 = 0x61;
+
+// PC speaker control port bits
+const CHANNEL_2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+/// Channel 0, low byte then high byte access, mode 2 (rate generator), binary counting
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b00_11_010_0;
+/// Channel 2, low byte then high byte access, mode 0 (interrupt on terminal count), binary counting
+const CHANNEL_2_ONE_SHOT: u8 = 0b10_11_000_0;
+
+static CHANNEL_0: Mutex<Port<u8>> = Mutex::new(Port::new(CHANNEL_0_PORT));
+static COMMAND: Mutex<Port<u8>> = Mutex::new(Port::new(COMMAND_PORT));
+
+/// Make channel 0 interrupt periodically at `frequency` Hz
+///
+/// Returns the divisor of the base frequency, since the actual frequency is rounded to one
+pub fn set_frequency(frequency: u32) -> u32 {
+    // A reload value of 0 means 65536, the slowest rate
+    let divisor = (BASE_FREQUENCY / frequency.max(1)).max(1).min(0x10000);
+
+    let mut channel_0 = CHANNEL_0.lock();
+    unsafe {
+        COMMAND.lock().write(CHANNEL_0_RATE_GENERATOR);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+
+    divisor
+}
+
+/// Busy wait until channel 2 counted down from `count`, without using interrupts
+///
+/// This leaves channel 0 and the timer interrupt alone, so it can be used to calibrate other
+/// clocks at any time
+pub fn wait(count: u16) {
+    let mut speaker_control = Port::<u8>::new(SPEAKER_CONTROL_PORT);
+    let mut channel_2 = Port::<u8>::new(CHANNEL_2_PORT);
+
+    unsafe {
+        // Stop the counter and silence the speaker while loading the count
+        let control = speaker_control.read() & !(CHANNEL_2_GATE | SPEAKER_ENABLE);
+        speaker_control.write(control);
+
+        COMMAND.lock().write(CHANNEL_2_ONE_SHOT);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        // The output goes high once the count reaches 0
+        speaker_control.write(control | CHANNEL_2_GATE);
+        while speaker_control.read() & CHANNEL_2_OUTPUT == 0 {}
+
+        speaker_control.write(control);
+    }
+}
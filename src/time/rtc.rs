@@ -0,0 +1,268 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::acpi;
+use crate::interrupts::register_irq;
+
+use super::Instant;
+
+/// The ports selecting a CMOS register and accessing it
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+// CMOS registers of the real-time clock
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+/// Status A: the time registers are being updated and may be inconsistent
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status A: the rate selecting the frequency of periodic interrupts
+const RATE_MASK: u8 = 0x0f;
+/// Status B: the hours are in 24-hour format
+const HOURS_24: u8 = 1 << 1;
+/// Status B: the time registers are binary instead of BCD
+const BINARY: u8 = 1 << 2;
+/// Status B and C: the interrupt at the end of every update, once per second
+const UPDATE_INTERRUPT: u8 = 1 << 4;
+/// Status B and C: the periodic interrupt
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+/// Status C: the alarm interrupt
+const ALARM_INTERRUPT: u8 = 1 << 5;
+/// The hours register: the hour is after noon, in 12-hour format
+const HOUR_PM: u8 = 1 << 7;
+
+/// The ISA interrupt of the RTC
+const IRQ: u8 = 8;
+
+/// The century assumed when the FADT doesn't list a century register
+const DEFAULT_CENTURY: u16 = 20;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+    index: Port::new(INDEX_PORT),
+    data: Port::new(DATA_PORT),
+});
+
+/// The time read at the last update interrupt, and when it was read
+static LAST_UPDATE: Mutex<Option<(u64, Instant)>> = Mutex::new(None);
+
+/// The amount of periodic interrupts
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+
+    /// Read the time registers, which may be in BCD and 12-hour format
+    fn read_registers(&mut self, century_register: u8) -> [u8; 7] {
+        while self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+
+        [
+            self.read(SECONDS),
+            self.read(MINUTES),
+            self.read(HOURS),
+            self.read(DAY),
+            self.read(MONTH),
+            self.read(YEAR),
+            if century_register != 0 {
+                self.read(century_register)
+            } else {
+                0
+            },
+        ]
+    }
+
+    fn read_date_time(&mut self) -> DateTime {
+        let century_register = acpi::fadt().map_or(0, |fadt| fadt.century);
+
+        // An update may start right after the flag was checked, so read until the time is stable
+        let mut registers = self.read_registers(century_register);
+        loop {
+            let again = self.read_registers(century_register);
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+
+        let status_b = self.read(STATUS_B);
+        let decode = |value: u8| {
+            if status_b & BINARY != 0 {
+                value
+            } else {
+                (value >> 4) * 10 + (value & 0x0f)
+            }
+        };
+
+        let mut hour = decode(registers[2] & !HOUR_PM);
+        if status_b & HOURS_24 == 0 {
+            // 12 AM is midnight and 12 PM is noon
+            hour %= 12;
+            if registers[2] & HOUR_PM != 0 {
+                hour += 12;
+            }
+        }
+        let century = if century_register != 0 {
+            u16::from(decode(registers[6]))
+        } else {
+            DEFAULT_CENTURY
+        };
+
+        DateTime {
+            year: century * 100 + u16::from(decode(registers[5])),
+            month: decode(registers[4]),
+            day: decode(registers[3]),
+            hour,
+            minute: decode(registers[1]),
+            second: decode(registers[0]),
+        }
+    }
+}
+
+/// A date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The seconds since 1970-01-01 00:00:00 UTC
+    pub fn unix_timestamp(&self) -> u64 {
+        // Count years from March, so the leap day is at the end of a year
+        let year = i64::from(self.year) - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month_from_march = (i64::from(self.month) + 9) % 12;
+        let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        // The days from 0000-03-01 to 1970-01-01
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days.max(0) as u64 * SECONDS_PER_DAY
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Read the time and enable the update interrupt, which keeps it current
+pub fn init() -> DateTime {
+    let date_time = interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let date_time = cmos.read_date_time();
+        *LAST_UPDATE.lock() = Some((date_time.unix_timestamp(), Instant::now()));
+
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | UPDATE_INTERRUPT);
+        // Acknowledge any pending interrupt, or the RTC won't raise another one
+        cmos.read(STATUS_C);
+
+        date_time
+    });
+    register_irq(IRQ, "RTC", handle_interrupt);
+
+    date_time
+}
+
+/// Read the date and time from the RTC
+pub fn date_time() -> DateTime {
+    interrupts::without_interrupts(|| CMOS.lock().read_date_time())
+}
+
+/// The current UNIX timestamp
+pub fn now() -> u64 {
+    let last_update = interrupts::without_interrupts(|| *LAST_UPDATE.lock());
+    match last_update {
+        Some((timestamp, read_at)) => timestamp + read_at.elapsed().as_secs(),
+        None => date_time().unix_timestamp(),
+    }
+}
+
+/// Make the RTC interrupt periodically at `frequency` Hz, which is rounded down to a power of two
+/// between 2 and 8192, or stop periodic interrupts if it's 0
+#[allow(dead_code)]
+pub fn set_periodic_frequency(frequency: u32) {
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        if frequency == 0 {
+            cmos.write(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+            return;
+        }
+
+        // The frequency is 32768 >> (rate - 1)
+        let frequency = frequency.max(2).min(8192);
+        let rate = 16 - (31 - frequency.leading_zeros()) as u8;
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & !RATE_MASK) | rate);
+        cmos.write(STATUS_B, status_b | PERIODIC_INTERRUPT);
+    });
+}
+
+/// The amount of periodic interrupts since they were enabled
+#[allow(dead_code)]
+pub fn periodic_interrupts() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Acknowledge an interrupt of the RTC, and read the time if it was an update interrupt
+///
+/// Returns whether the RTC raised the interrupt
+fn handle_interrupt() -> bool {
+    let mut cmos = CMOS.lock();
+    let status_c = cmos.read(STATUS_C);
+
+    if status_c & PERIODIC_INTERRUPT != 0 {
+        PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    }
+    if status_c & UPDATE_INTERRUPT != 0 {
+        let timestamp = cmos.read_date_time().unix_timestamp();
+        *LAST_UPDATE.lock() = Some((timestamp, Instant::now()));
+    }
+
+    status_c & (PERIODIC_INTERRUPT | ALARM_INTERRUPT | UPDATE_INTERRUPT) != 0
+}
@@ -0,0 +1,168 @@
+use core::{convert::TryFrom, time::Duration};
+
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::interrupts::{
+    apic::{self, TimerMode},
+    TIMER_VECTOR,
+};
+
+use super::{
+    busy_wait, hpet, tsc, Clock, Instant, FEMTOSECONDS_PER_NANOSECOND, NANOSECONDS_PER_SECOND,
+};
+
+/// How long the local APIC timer is measured against the reference clock, in milliseconds
+const CALIBRATION_MILLISECONDS: u64 = 10;
+
+/// The timer scheduling interrupts for deadlines, if the timer is tickless
+static EVENT_TIMER: Once<EventTimer> = Once::new();
+
+/// The pending deadlines, sorted with the earliest one last
+static DEADLINES: Mutex<Vec<Instant>> = Mutex::new(Vec::new());
+
+/// A timer that can interrupt once at a given time
+#[derive(Debug, Clone, Copy)]
+enum EventTimer {
+    /// The local APIC timer in TSC-deadline mode, with the TSC frequency in Hz
+    TscDeadline { frequency: u64 },
+    /// The local APIC timer in one-shot mode, with its frequency in Hz
+    LocalApic { frequency: u64 },
+    /// A HPET comparator, with the period of the main counter in femtoseconds
+    Hpet { period: u64 },
+}
+
+/// Set up a one-shot timer to replace the periodic PIT interrupt, if the APIC is used
+///
+/// Returns the name of the timer, or `None` if the PIT has to keep ticking
+pub fn init(clock: Clock) -> Option<&'static str> {
+    if !apic::is_enabled() {
+        return None;
+    }
+
+    // Interrupt handlers use the timer registers, so they must not run while the timer is set up
+    let timer = interrupts::without_interrupts(|| {
+        // The local APIC timer may stop in deep C-states, where the HPET keeps running
+        let timer = if !apic::timer_always_running() && hpet::init_event_timer(TIMER_VECTOR) {
+            EventTimer::Hpet {
+                period: hpet::period().unwrap(),
+            }
+        } else if let (Clock::Tsc { frequency, .. }, true) = (clock, apic::tsc_deadline_supported())
+        {
+            apic::set_timer_mode(TIMER_VECTOR, TimerMode::TscDeadline);
+            EventTimer::TscDeadline { frequency }
+        } else {
+            apic::set_timer_mode(TIMER_VECTOR, TimerMode::OneShot);
+            EventTimer::LocalApic {
+                frequency: calibrate_local_apic(),
+            }
+        };
+
+        let timer = EVENT_TIMER.call_once(|| timer);
+        program(timer, DEADLINES.lock().last().cloned());
+        *timer
+    });
+
+    Some(match timer {
+        EventTimer::TscDeadline { .. } => "local APIC TSC-deadline",
+        EventTimer::LocalApic { .. } => "local APIC one-shot",
+        EventTimer::Hpet { .. } => "HPET",
+    })
+}
+
+/// Schedule a timer interrupt at `deadline`, if the timer is tickless
+pub fn add_deadline(deadline: Instant) {
+    let timer = match EVENT_TIMER.get() {
+        Some(timer) => timer,
+        None => return,
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut deadlines = DEADLINES.lock();
+        let position = deadlines
+            .iter()
+            .position(|&pending| pending < deadline)
+            .unwrap_or(deadlines.len());
+        deadlines.insert(position, deadline);
+
+        if position == deadlines.len() - 1 {
+            program(timer, Some(deadline));
+        }
+    });
+}
+
+/// Remove the deadlines that passed and schedule the interrupt for the next one
+///
+/// This is called from the timer interrupt handler
+pub fn expire() {
+    let timer = match EVENT_TIMER.get() {
+        Some(timer) => timer,
+        None => return,
+    };
+
+    let now = Instant::now();
+    let mut deadlines = DEADLINES.lock();
+    while deadlines.last().map_or(false, |&deadline| deadline <= now) {
+        deadlines.pop();
+    }
+    program(timer, deadlines.last().cloned());
+}
+
+/// Make the timer interrupt at `deadline`, or disarm it
+///
+/// The interrupt may come early when the timer is less precise than the clock, in which case
+/// `expire` programs it again
+fn program(timer: &EventTimer, deadline: Option<Instant>) {
+    let remaining = deadline.map(|deadline| deadline.duration_since(Instant::now()));
+
+    match (*timer, remaining) {
+        (EventTimer::TscDeadline { frequency }, Some(remaining)) => {
+            apic::set_tsc_deadline(tsc::read().saturating_add(cycles(remaining, frequency)))
+        }
+        (EventTimer::TscDeadline { .. }, None) => apic::set_tsc_deadline(0),
+        (EventTimer::LocalApic { frequency }, Some(remaining)) => {
+            let count = cycles(remaining, frequency)
+                .max(1)
+                .min(u64::from(u32::max_value()));
+            apic::start_timer(count as u32)
+        }
+        (EventTimer::LocalApic { .. }, None) => apic::start_timer(0),
+        (EventTimer::Hpet { period }, Some(remaining)) => {
+            let ticks = divide_rounding_up(
+                remaining.as_nanos() * FEMTOSECONDS_PER_NANOSECOND,
+                u128::from(period),
+            );
+            // Longer timeouts could wrap a 32-bit comparator
+            let ticks = ticks.max(1).min(u128::from(u32::max_value() / 2));
+            hpet::set_timeout(ticks as u64)
+        }
+        (EventTimer::Hpet { .. }, None) => hpet::set_timeout(0),
+    }
+}
+
+/// The amount of cycles at `frequency` in `duration`, rounded up and saturating at `u64::MAX`
+fn cycles(duration: Duration, frequency: u64) -> u64 {
+    let cycles = divide_rounding_up(
+        duration.as_nanos() * u128::from(frequency),
+        NANOSECONDS_PER_SECOND,
+    );
+    u64::try_from(cycles).unwrap_or(u64::max_value())
+}
+
+fn divide_rounding_up(dividend: u128, divisor: u128) -> u128 {
+    (dividend + divisor - 1) / divisor
+}
+
+/// Measure the frequency of the local APIC timer in one-shot mode, in Hz
+fn calibrate_local_apic() -> u64 {
+    let elapsed = interrupts::without_interrupts(|| {
+        apic::start_timer(u32::max_value());
+        busy_wait(CALIBRATION_MILLISECONDS);
+        let elapsed = u32::max_value() - apic::timer_count();
+        apic::start_timer(0);
+        elapsed
+    });
+
+    u64::from(elapsed) * 1000 / CALIBRATION_MILLISECONDS
+}
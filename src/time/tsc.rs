@@ -0,0 +1,37 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use super::busy_wait;
+
+/// How long the TSC is measured against the reference clock, in milliseconds
+const CALIBRATION_MILLISECONDS: u64 = 50;
+
+/// The first extended CPUID leaf, and the one with the invariant TSC flag
+const EXTENDED_FEATURES: u32 = 0x8000_0000;
+const ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+/// Read the time stamp counter
+#[allow(unused_unsafe)]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether the TSC runs at a constant rate in all power states, so it can be used as a clock
+#[allow(unused_unsafe)]
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(EXTENDED_FEATURES) }.eax;
+    if max_extended_leaf < ADVANCED_POWER_MANAGEMENT {
+        return false;
+    }
+
+    // CPUID.80000007H:EDX.InvariantTSC[bit 8]
+    unsafe { __cpuid(ADVANCED_POWER_MANAGEMENT) }.edx & (1 << 8) != 0
+}
+
+/// Measure the frequency of the TSC in Hz
+pub fn calibrate() -> u64 {
+    let start = read();
+    busy_wait(CALIBRATION_MILLISECONDS);
+    let end = read();
+
+    (end - start) * 1000 / CALIBRATION_MILLISECONDS
+}
@@ -0,0 +1,254 @@
+use core::{
+    convert::TryFrom,
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{tickless, Instant};
+
+/// Each level of the wheel has `1 << SLOT_BITS` slots
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+/// A slot of the first level spans a millisecond, and a slot of each next level spans a whole
+/// rotation of the previous one
+const LEVELS: usize = 4;
+/// The longest delay the wheel can hold, in milliseconds, later timers are moved down again when
+/// their slot is reached
+const MAXIMUM_DELAY: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+const NANOSECONDS_PER_MILLISECOND: u64 = 1_000_000;
+
+const EMPTY_SLOT: Vec<Timer> = Vec::new();
+const EMPTY_LEVEL: [Vec<Timer>; SLOTS] = [EMPTY_SLOT; SLOTS];
+
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel {
+    current: 0,
+    levels: [EMPTY_LEVEL; LEVELS],
+    running: Vec::new(),
+});
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a timer, to cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: TimerId,
+    /// The millisecond at which the timer expires
+    expires: u64,
+    callback: Callback,
+}
+
+enum Callback {
+    Once(Box<dyn FnOnce() + Send>),
+    /// A callback that runs every `period` milliseconds
+    Periodic {
+        period: u64,
+        callback: Box<dyn FnMut() + Send>,
+    },
+}
+
+/// A hierarchical timing wheel, where timers are sorted into slots by when they expire
+///
+/// Timers far in the future are kept in the coarse slots of the higher levels, and are moved down to
+/// the finer levels as time passes
+struct TimerWheel {
+    /// The last millisecond whose timers were run
+    current: u64,
+    levels: [[Vec<Timer>; SLOTS]; LEVELS],
+    /// The periodic timers whose callbacks are running, which are added again unless cancelled
+    running: Vec<TimerId>,
+}
+
+impl TimerWheel {
+    fn insert(&mut self, timer: Timer) {
+        let delay = timer
+            .expires
+            .saturating_sub(self.current)
+            .max(1)
+            .min(MAXIMUM_DELAY);
+        let expires = self.current + delay;
+
+        // The first level with a rotation longer than the delay, so the slot isn't reached early
+        let level = (0..LEVELS)
+            .find(|&level| delay >> (SLOT_BITS * (level as u32 + 1)) == 0)
+            .unwrap();
+        let slot = (expires >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        self.levels[level][slot].push(timer);
+    }
+
+    /// Advance the wheel to `now`, and return the timers that expired
+    fn advance(&mut self, now: u64) -> Vec<Timer> {
+        let mut expired = Vec::new();
+
+        // Stepping through a long gap would take long, so sort all timers again instead
+        if now > self.current + SLOTS as u64 {
+            let timers: Vec<Timer> = self
+                .levels
+                .iter_mut()
+                .flat_map(|level| level.iter_mut())
+                .flat_map(|slot| mem::replace(slot, Vec::new()))
+                .collect();
+
+            self.current = now;
+            for timer in timers {
+                self.expire_or_insert(timer, &mut expired);
+            }
+            return expired;
+        }
+
+        while self.current < now {
+            self.current += 1;
+
+            // When a level completes a rotation, the next slot of the level above is moved down,
+            // starting at the highest level so its timers can be moved down further
+            let completed_levels = (1..LEVELS)
+                .take_while(|&level| self.current & ((1 << (SLOT_BITS * level as u32)) - 1) == 0)
+                .count();
+            for level in (1..=completed_levels).rev() {
+                let slot = (self.current >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+                for timer in mem::replace(&mut self.levels[level][slot], Vec::new()) {
+                    self.expire_or_insert(timer, &mut expired);
+                }
+            }
+
+            let slot = self.current as usize % SLOTS;
+            for timer in mem::replace(&mut self.levels[0][slot], Vec::new()) {
+                self.expire_or_insert(timer, &mut expired);
+            }
+        }
+
+        expired
+    }
+
+    fn expire_or_insert(&mut self, timer: Timer, expired: &mut Vec<Timer>) {
+        if timer.expires <= self.current {
+            if let Callback::Periodic { .. } = timer.callback {
+                self.running.push(timer.id);
+            }
+            expired.push(timer);
+        } else {
+            self.insert(timer);
+        }
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        for slot in self.levels.iter_mut().flat_map(|level| level.iter_mut()) {
+            if let Some(position) = slot.iter().position(|timer| timer.id == id) {
+                slot.swap_remove(position);
+                return true;
+            }
+        }
+
+        match self.running.iter().position(|&running| running == id) {
+            Some(position) => {
+                self.running.swap_remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Run `callback` from the timer interrupt once `deadline` passed
+///
+/// The callback runs with interrupts disabled, so it should be short
+pub fn add_timer<F: FnOnce() + Send + 'static>(deadline: Instant, callback: F) -> TimerId {
+    add(Timer {
+        id: next_id(),
+        expires: milliseconds_rounding_up(deadline.nanoseconds),
+        callback: Callback::Once(Box::new(callback)),
+    })
+}
+
+/// Run `callback` from the timer interrupt every `period`, starting one period from now
+///
+/// The period is rounded up to whole milliseconds
+pub fn add_periodic_timer<F: FnMut() + Send + 'static>(period: Duration, callback: F) -> TimerId {
+    let nanoseconds = u64::try_from(period.as_nanos()).unwrap_or(u64::max_value());
+    let period = milliseconds_rounding_up(nanoseconds).max(1);
+
+    add(Timer {
+        id: next_id(),
+        expires: milliseconds_rounding_up(Instant::now().nanoseconds) + period,
+        callback: Callback::Periodic {
+            period,
+            callback: Box::new(callback),
+        },
+    })
+}
+
+/// Cancel a timer, so its callback doesn't run again
+///
+/// Returns `false` if the timer doesn't exist anymore
+pub fn cancel_timer(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| WHEEL.lock().remove(id))
+}
+
+/// Run the callbacks of the expired timers
+///
+/// This is called from the timer interrupt handler
+pub fn run() {
+    let now = Instant::now().nanoseconds / NANOSECONDS_PER_MILLISECOND;
+    let expired = WHEEL.lock().advance(now);
+
+    // The wheel isn't locked while the callbacks run, so they can add and cancel timers
+    for Timer {
+        id,
+        expires,
+        callback,
+    } in expired
+    {
+        match callback {
+            Callback::Once(callback) => callback(),
+            Callback::Periodic {
+                period,
+                mut callback,
+            } => {
+                callback();
+
+                let mut wheel = WHEEL.lock();
+                if let Some(position) = wheel.running.iter().position(|&running| running == id) {
+                    wheel.running.swap_remove(position);
+                    // Periods that were missed are skipped
+                    let expires = (expires + period).max(wheel.current + 1);
+                    drop(wheel);
+
+                    add(Timer {
+                        id,
+                        expires,
+                        callback: Callback::Periodic { period, callback },
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn add(timer: Timer) -> TimerId {
+    let id = timer.id;
+    let deadline = Instant {
+        nanoseconds: timer.expires * NANOSECONDS_PER_MILLISECOND,
+    };
+
+    interrupts::without_interrupts(|| WHEEL.lock().insert(timer));
+    // A tickless timer has to interrupt when the timer expires
+    tickless::add_deadline(deadline);
+
+    id
+}
+
+fn next_id() -> TimerId {
+    TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Saturates, so the furthest deadline stays the furthest millisecond
+fn milliseconds_rounding_up(nanoseconds: u64) -> u64 {
+    nanoseconds.saturating_add(NANOSECONDS_PER_MILLISECOND - 1) / NANOSECONDS_PER_MILLISECOND
+}
@@ -10,7 +10,11 @@ use x86_64::instructions::interrupts::without_interrupts;
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     column_position: 0,
     color_code: ColorCode::new(Color::Yellow, Color::Black),
-    buffer: unsafe { BufferPtr(NonNull::new_unchecked(0xb8000 as *mut _)) },
+    buffer: unsafe {
+        BufferPtr(NonNull::new_unchecked(
+            (crate::memory::KERNEL_OFFSET + 0xb8000) as *mut _,
+        ))
+    },
 });
 
 /// A formatting `print` function, using the VGA writer.
@@ -29,6 +33,7 @@ macro_rules! print {
 
 /// Prints to the screen using VGA, with a newline.
 macro_rules! println {
+    () => (print!("\n"));
     ($fmt:expr) => (print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
@@ -109,6 +114,19 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            // Backspace, which erases the previous character of the row
+            b'\x08' => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+
+                    let col = self.column_position;
+                    let color_code = self.color_code;
+                    self.buffer().chars[BUFFER_HEIGHT - 1][col] = ScreenChar {
+                        ascii_character: b' ',
+                        color_code,
+                    };
+                }
+            }
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();